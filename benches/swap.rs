@@ -0,0 +1,182 @@
+// Latency of switching between two contexts
+//
+// Run with `cargo bench`, it prints the average time of a round trip, which
+// is two context switches.
+//
+// On x86_64 Linux it also compares the way the System V switch used to save
+// the floating point state, all of %xmm0-%xmm5, against the MXCSR and x87
+// control word it saves now. Both are bare copies of the switch without
+// anything else around them, so only the floating point part differs.
+
+extern crate context;
+
+use std::time::Instant;
+
+use context::{Context, Stack, Transfer};
+
+const STACK_SIZE: usize = 2 * 1024 * 1024;
+const ROUNDS: u32 = 1_000_000;
+const RUNS: u32 = 10;
+
+// The best of RUNS runs of ROUNDS calls to `round_trip`, in ns per call
+fn measure<F: FnMut()>(mut round_trip: F) -> f64 {
+    (0..RUNS).map(|_| {
+        let start = Instant::now();
+        for _ in 0..ROUNDS {
+            round_trip();
+        }
+        let elapsed = start.elapsed();
+        (elapsed.as_secs() * 1_000_000_000 + elapsed.subsec_nanos() as u64) as f64 / ROUNDS as f64
+    }).fold(f64::INFINITY, f64::min)
+}
+
+fn bench_swap() {
+    let mut cur = Context::empty();
+
+    let mut stk = Stack::new(STACK_SIZE);
+    let ctx = Context::with_closure(&mut stk, |t| {
+        let parent = t.from;
+        let mut me = Context::empty();
+        // Tell the parent where to swap to from now on
+        Context::jump(&mut me, unsafe { &*parent }, 0);
+        loop {
+            Context::swap(&mut me, unsafe { &*parent });
+        }
+    });
+
+    let t = Context::jump(&mut cur, &ctx, 0);
+    let child = unsafe { &*t.from };
+    let ns = measure(|| Context::swap(&mut cur, child));
+    println!("swap round trip: {:.1} ns", ns);
+}
+
+fn bench_jump() {
+    let mut cur = Context::empty();
+
+    let mut stk = Stack::new(STACK_SIZE);
+    let ctx = Context::with_closure(&mut stk, |mut t: Transfer| {
+        let mut me = Context::empty();
+        loop {
+            t = Context::jump(&mut me, unsafe { &*t.from }, t.data);
+        }
+    });
+
+    let mut child = Context::jump(&mut cur, &ctx, 0).from;
+    let ns = measure(|| child = Context::jump(&mut cur, unsafe { &*child }, 0).from);
+    println!("jump round trip: {:.1} ns", ns);
+}
+
+// Two copies of the integer part of the switch, one with each way of saving
+// the floating point state
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+mod fp_state {
+    use std::arch::global_asm;
+
+    use context::Stack;
+
+    use super::{measure, STACK_SIZE};
+
+    // rbx, rsp, rbp, rdi, r12-r15, the instruction pointer, a gap so the
+    // xmm registers are 16-byte aligned, xmm0-xmm5 at 10, MXCSR and x87
+    // control word at 22
+    #[repr(C, align(16))]
+    struct Regs([u64; 24]);
+
+    const FPCW: usize = 22;
+
+    type Switch = unsafe extern "C" fn(out_regs: *mut Regs, in_regs: *const Regs);
+
+    macro_rules! switch {
+        ($name:literal, $save:literal, $restore:literal) => {
+            global_asm!(
+                concat!(".globl ", $name),
+                concat!($name, ":"),
+                "mov %rbx, 0(%rdi)",
+                "mov %rsp, 8(%rdi)",
+                "mov %rbp, 16(%rdi)",
+                "mov %rdi, 24(%rdi)",
+                "mov %r12, 32(%rdi)",
+                "mov %r13, 40(%rdi)",
+                "mov %r14, 48(%rdi)",
+                "mov %r15, 56(%rdi)",
+                "lea 1f(%rip), %rax",
+                "mov %rax, 64(%rdi)",
+                $save,
+                "mov 0(%rsi), %rbx",
+                "mov 8(%rsi), %rsp",
+                "mov 16(%rsi), %rbp",
+                "mov 24(%rsi), %rdi",
+                "mov 32(%rsi), %r12",
+                "mov 40(%rsi), %r13",
+                "mov 48(%rsi), %r14",
+                "mov 56(%rsi), %r15",
+                $restore,
+                "jmp *64(%rsi)",
+                "1:",
+                "ret",
+                options(att_syntax)
+            );
+        }
+    }
+
+    switch!("bench_switch_xmm",
+            "movapd %xmm0, 80(%rdi)\n movapd %xmm1, 96(%rdi)\n movapd %xmm2, 112(%rdi)\n \
+             movapd %xmm3, 128(%rdi)\n movapd %xmm4, 144(%rdi)\n movapd %xmm5, 160(%rdi)",
+            "movapd 80(%rsi), %xmm0\n movapd 96(%rsi), %xmm1\n movapd 112(%rsi), %xmm2\n \
+             movapd 128(%rsi), %xmm3\n movapd 144(%rsi), %xmm4\n movapd 160(%rsi), %xmm5");
+    switch!("bench_switch_fpcw",
+            "stmxcsr 176(%rdi)\n fnstcw 180(%rdi)",
+            "ldmxcsr 176(%rsi)\n fldcw 180(%rsi)");
+
+    extern "C" {
+        fn bench_switch_xmm(out_regs: *mut Regs, in_regs: *const Regs);
+        fn bench_switch_fpcw(out_regs: *mut Regs, in_regs: *const Regs);
+    }
+
+    struct Fibers {
+        main: Regs,
+        child: Regs,
+        switch: Switch,
+    }
+
+    // Switches straight back, forever
+    extern "C" fn child(fibers: *mut Fibers) -> ! {
+        loop {
+            unsafe { ((*fibers).switch)(&mut (*fibers).child, &(*fibers).main) };
+        }
+    }
+
+    fn round_trip(switch: Switch) -> f64 {
+        let stack = Stack::new(STACK_SIZE);
+        let mut fibers = Box::new(Fibers {
+            main: Regs([0; 24]),
+            child: Regs([0; 24]),
+            switch,
+        });
+
+        // Entered as if called, with the default floating point state
+        let fibers: *mut Fibers = &mut *fibers;
+        let regs = unsafe { &mut (*fibers).child.0 };
+        regs[1] = (stack.end() as u64 & !15) - 8;
+        regs[3] = fibers as u64;
+        regs[8] = child as *const () as u64;
+        regs[FPCW] = 0x037F_0000_1F80;
+
+        measure(|| unsafe { switch(&mut (*fibers).main, &(*fibers).child) })
+    }
+
+    pub fn bench() {
+        let xmm = round_trip(bench_switch_xmm);
+        let fpcw = round_trip(bench_switch_fpcw);
+        println!("bare round trip saving %xmm0-%xmm5 (before): {:.1} ns", xmm);
+        println!("bare round trip saving MXCSR and x87 control word (after): {:.1} ns", fpcw);
+        println!("after - before: {:+.1} ns ({:+.1}%)", fpcw - xmm, (fpcw - xmm) / xmm * 100.0);
+    }
+}
+
+fn main() {
+    bench_swap();
+    bench_jump();
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    fp_state::bench();
+}
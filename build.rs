@@ -1,27 +1,45 @@
-extern crate gcc;
+extern crate cc;
 
 use std::path::PathBuf;
 use std::env;
 
-const LIB_NAME: &'static str = "libctxswtch.a";
+const LIB_NAME: &str = "ctxswtch";
 
 fn main() {
+    if env::var_os("CARGO_FEATURE_UCONTEXT").is_some() {
+        cc::Build::new().file("src/asm/ucontext/_context.c").compile(LIB_NAME);
+        return;
+    }
+
+    // The routines are in src/asm/*/mod.rs then
+    if env::var_os("CARGO_FEATURE_GLOBAL_ASM").is_some() {
+        return;
+    }
+
+    // `cfg!(target_arch)` would be the host here, which breaks cross compiling
+    let target = env::var("TARGET").unwrap();
     let arch =
-        if cfg!(target_arch = "x86_64") {
+        if target.starts_with("x86_64") {
             "x86_64"
-        } else if cfg!(target_arch = "x86") {
+        } else if target.starts_with("i686") || target.starts_with("i586") {
             "i686"
-        } else if cfg!(target_arch = "arm") {
+        } else if target.starts_with("aarch64") {
+            "aarch64"
+        } else if target.starts_with("arm") {
             "arm"
-        } else if cfg!(target_arch = "mips") {
-            "mips"
-        } else if cfg!(target_arch = "mipsel") {
+        } else if target.starts_with("powerpc64le") {
+            "powerpc64le"
+        } else if target.starts_with("riscv64") {
+            "riscv64"
+        } else if target.starts_with("mipsel") {
             "mipsel"
+        } else if target.starts_with("mips") {
+            "mips"
         } else {
-            panic!("Unsupported architecture: {}", env::var("TARGET").unwrap());
+            panic!("Unsupported architecture: {}", target);
         };
     let src_path = &["src", "asm", arch, "_context.S"].iter().collect::<PathBuf>();
-    gcc::compile_library(LIB_NAME, &[src_path.to_str().unwrap()]);
+    cc::Build::new().file(src_path).compile(LIB_NAME);
 
 // seems like this line is no need actually
 //    println!("cargo:rustc-flags=-l ctxswtch:static");
@@ -20,22 +20,20 @@
 //  FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
 //  DEALINGS IN THE SOFTWARE.
 
-#![feature(catch_panic, fnbox)]
 extern crate context;
 extern crate libc;
 
 use std::iter::Iterator;
-use std::mem::transmute;
 use std::cell::UnsafeCell;
+use std::sync::OnceLock;
 use std::default::Default;
 use std::ops::DerefMut;
 use std::fmt;
-use std::thread;
-use std::boxed::FnBox;
+use std::panic::{self, AssertUnwindSafe};
 use std::any::Any;
 
-use context::Context;
-use context::stack::{Stack, StackPool};
+use context::{Context, Transfer};
+use context::stack::{SharedStackPool, Stack};
 
 pub struct Options {
     stack_size: usize,
@@ -53,24 +51,34 @@ impl Default for Options {
 
 #[derive(Debug)]
 pub enum CoroError {
-    Panicking(Box<Any + Send>),
+    Panicking(Box<dyn Any + Send>),
     Panicked,
 }
 
 pub type CoroResult<T> = Result<T, CoroError>;
 
-thread_local!(static STACK_POOL: UnsafeCell<StackPool> = UnsafeCell::new(StackPool::new()));
+// Shared by all threads, as a coroutine may be dropped on another thread than
+// the one it got spawned on
+fn stack_pool() -> &'static SharedStackPool {
+    static STACK_POOL: OnceLock<SharedStackPool> = OnceLock::new();
+    STACK_POOL.get_or_init(SharedStackPool::new)
+}
 
 struct ForceUnwind;
 
+/// Runs on top of a suspended coroutine to unwind its stack
+fn force_unwind(_: Transfer) -> Transfer {
+    panic::resume_unwind(Box::new(ForceUnwind));
+}
+
 /// Initialization function for make context
 extern "C" fn coroutine_initialize(_: usize, f: *mut libc::c_void) -> ! {
     {
-        let func: Box<Box<FnBox()>> = unsafe {
-            Box::from_raw(f as *mut Box<FnBox()>)
+        let func: Box<Box<dyn FnOnce()>> = unsafe {
+            Box::from_raw(f as *mut Box<dyn FnOnce()>)
         };
 
-        func();
+        (*func)();
     }
 
     unreachable!("Never reach here");
@@ -81,10 +89,8 @@ enum State {
     Created,
     Running,
     Finished,
-    ForceUnwind,
 }
 
-#[allow(raw_pointer_derive)]
 #[derive(Debug)]
 struct CoroutineImpl<T = ()>
     where T: Send
@@ -119,10 +125,6 @@ impl<T> CoroutineImpl<T>
     unsafe fn yield_back(&mut self) -> Option<T> {
         Context::swap(&mut self.context, &self.parent);
 
-        if let State::ForceUnwind = self.state {
-            panic!("Coroutine is going to be destroyed, unwinding stack ...");
-        }
-
         match self.result.take() {
             None => None,
             Some(Ok(x)) => (*x).take(),
@@ -163,8 +165,8 @@ impl<T> CoroutineImpl<T>
 
     unsafe fn force_unwind(&mut self) {
         if let State::Running = self.state {
-            self.state = State::ForceUnwind;
-            let _ = self.resume();
+            Context::swap_ontop(&mut self.parent, &self.context, 0, force_unwind);
+            self.result.take();
         }
     }
 }
@@ -176,11 +178,9 @@ impl<T> Drop for CoroutineImpl<T>
         unsafe {
             self.force_unwind();
         }
-        STACK_POOL.with(|pool| unsafe {
-            if let Some(stack) = self.stack.take() {
-                (&mut *pool.get()).give_stack(stack);
-            }
-        });
+        if let Some(stack) = self.stack.take() {
+            stack_pool().give_stack(stack);
+        }
     }
 }
 
@@ -205,9 +205,7 @@ impl<T> Coroutine<T>
     pub fn spawn_opts<F>(f: F, opts: Options) -> Coroutine<T>
         where F: FnOnce(CoroutineRef<T>) + Send + 'static
     {
-        let mut stack = STACK_POOL.with(|pool| unsafe {
-            (&mut *pool.get()).take_stack(opts.stack_size)
-        });
+        let mut stack = stack_pool().take_stack(opts.stack_size);
 
         let mut coro = Box::new(CoroutineImpl {
             parent: Context::empty(),
@@ -229,14 +227,14 @@ impl<T> Coroutine<T>
 
         // Coroutine function wrapper
         // Responsible for calling the function and dealing with panicking
-        let wrapper = move|| -> ! {
-            let ret = unsafe {
-                let puller_ref = puller_ref.clone();
-                thread::catch_panic(move|| {
-                    let coro_ref: &mut CoroutineImpl<T> = &mut *puller_ref.coro;
+        let wrapper = move|| {
+            let ret = {
+                let puller_ref = puller_ref;
+                panic::catch_unwind(AssertUnwindSafe(move|| {
+                    let coro_ref: &mut CoroutineImpl<T> = unsafe { &mut *puller_ref.coro };
                     coro_ref.state = State::Running;
                     f(puller_ref)
-                })
+                }))
             };
 
             unsafe {
@@ -247,7 +245,7 @@ impl<T> Coroutine<T>
             let is_panicked = match ret {
                 Ok(..) => false,
                 Err(err) => {
-                    if let None = err.downcast_ref::<ForceUnwind>() {
+                    if !err.is::<ForceUnwind>() {
                         {
                             use std::io::stderr;
                             use std::io::Write;
@@ -282,7 +280,7 @@ impl<T> Coroutine<T>
             }
         };
 
-        let callback: Box<FnBox()> = Box::new(wrapper);
+        let callback: Box<dyn FnOnce()> = Box::new(wrapper);
 
         coro.context.init_with(coroutine_initialize, 0, Box::into_raw(Box::new(callback)) as *mut libc::c_void, &mut stack);
         coro.stack = Some(stack);
@@ -335,9 +333,7 @@ impl<T> Clone for CoroutineRef<T>
     where T: Send,
 {
     fn clone(&self) -> CoroutineRef<T> {
-        CoroutineRef {
-            coro: self.coro,
-        }
+        *self
     }
 }
 
@@ -355,7 +351,7 @@ impl<T> CoroutineRef<T>
     #[inline]
     pub fn yield_back(&self) -> Option<T> {
         unsafe {
-            let coro: &mut CoroutineImpl<T> = transmute(self.coro);
+            let coro: &mut CoroutineImpl<T> = &mut *self.coro;
             coro.yield_back()
         }
     }
@@ -363,7 +359,7 @@ impl<T> CoroutineRef<T>
     #[inline]
     pub fn yield_with(&self, data: T) -> Option<T> {
         unsafe {
-            let coro: &mut CoroutineImpl<T> = transmute(self.coro);
+            let coro: &mut CoroutineImpl<T> = &mut *self.coro;
             coro.yield_with(data)
         }
     }
@@ -378,7 +374,7 @@ impl<T> CoroutineRef<T>
     #[inline]
     pub fn take_data(&self) -> Option<T> {
         unsafe {
-            let coro: &mut CoroutineImpl<T> = transmute(self.coro);
+            let coro: &mut CoroutineImpl<T> = &mut *self.coro;
             coro.take_data()
         }
     }
@@ -391,7 +387,7 @@ impl<T> Iterator for Coroutine<T>
 
     fn next(&mut self) -> Option<CoroResult<T>> {
         match self.resume() {
-            Ok(r) => r.map(|x| Ok(x)),
+            Ok(r) => r.map(Ok),
             Err(err) => Some(Err(err)),
         }
     }
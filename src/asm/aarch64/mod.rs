@@ -0,0 +1,92 @@
+// The routines of _context.S next to this file, for the `global-asm` feature.
+// Keep both in sync, see there for the details and the comments.
+
+use std::arch::global_asm;
+
+// Save x19-x30, sp and d8-d15 into $regs
+macro_rules! save_registers {
+    ($regs:expr) => (concat!(
+        "stp x19, x20, [", $regs, ", #0]\n",
+        "stp x21, x22, [", $regs, ", #16]\n",
+        "stp x23, x24, [", $regs, ", #32]\n",
+        "stp x25, x26, [", $regs, ", #48]\n",
+        "stp x27, x28, [", $regs, ", #64]\n",
+        "stp x29, x30, [", $regs, ", #80]\n",
+        "mov x9, sp\n",
+        "str x9, [", $regs, ", #96]\n",
+        "stp d8, d9, [", $regs, ", #104]\n",
+        "stp d10, d11, [", $regs, ", #120]\n",
+        "stp d12, d13, [", $regs, ", #136]\n",
+        "stp d14, d15, [", $regs, ", #152]\n",
+    ))
+}
+
+// Restore x19-x30, sp and d8-d15 from $regs
+macro_rules! restore_registers {
+    ($regs:expr) => (concat!(
+        "ldp x19, x20, [", $regs, ", #0]\n",
+        "ldp x21, x22, [", $regs, ", #16]\n",
+        "ldp x23, x24, [", $regs, ", #32]\n",
+        "ldp x25, x26, [", $regs, ", #48]\n",
+        "ldp x27, x28, [", $regs, ", #64]\n",
+        "ldp x29, x30, [", $regs, ", #80]\n",
+        "ldr x9, [", $regs, ", #96]\n",
+        "mov sp, x9\n",
+        "ldp d8, d9, [", $regs, ", #104]\n",
+        "ldp d10, d11, [", $regs, ", #120]\n",
+        "ldp d12, d13, [", $regs, ", #136]\n",
+        "ldp d14, d15, [", $regs, ", #152]\n",
+    ))
+}
+
+global_asm!(concat!(
+    ".text\n",
+    ".align 2\n",
+
+    // swap_registers(registers_t *oregs, registers_t *regs)
+    ".globl ", c_symbol!("rust_swap_registers"), "\n",
+    c_symbol!("rust_swap_registers"), ":\n",
+    save_registers!("x0"),
+    restore_registers!("x1"),
+    "mov x0, #0\n",
+    "ret\n",
+
+    // save_registers(registers_t *regs)
+    ".globl ", c_symbol!("rust_save_registers"), "\n",
+    c_symbol!("rust_save_registers"), ":\n",
+    save_registers!("x0"),
+    "ret\n",
+
+    // load_registers(registers_t *regs)
+    ".globl ", c_symbol!("rust_load_registers"), "\n",
+    c_symbol!("rust_load_registers"), ":\n",
+    restore_registers!("x0"),
+    "mov x0, #0\n",
+    "ret\n",
+
+    // jump_registers(registers_t *oregs, registers_t *regs, transfer_t *t) -> transfer_t *
+    ".globl ", c_symbol!("rust_jump_registers"), "\n",
+    c_symbol!("rust_jump_registers"), ":\n",
+    save_registers!("x0"),
+    restore_registers!("x1"),
+    "mov x0, x2\n",
+    "ret\n",
+
+    // ontop_registers(registers_t *oregs, registers_t *regs, transfer_t *t,
+    //                 transfer_t *(*fn)(transfer_t *)) -> transfer_t *
+    ".globl ", c_symbol!("rust_ontop_registers"), "\n",
+    c_symbol!("rust_ontop_registers"), ":\n",
+    save_registers!("x0"),
+    restore_registers!("x1"),
+    "mov x0, x2\n",
+    "br x3\n",
+
+    // The first code run by a new context
+    ".globl ", c_symbol!("rust_bootstrap_green_task"), "\n",
+    c_symbol!("rust_bootstrap_green_task"), ":\n",
+    "mov x2, x0\n",
+    "mov x0, x19\n",
+    "mov x1, x20\n",
+    "mov x30, #0\n",
+    "br x21\n",
+));
@@ -0,0 +1,103 @@
+// The routines of _context.S next to this file, for the `global-asm` feature.
+// Keep both in sync, see there for the details and the comments.
+
+use std::arch::global_asm;
+
+// Save r0, r3-r12, sp and lr into $regs
+macro_rules! save_registers {
+    ($regs:expr) => (concat!(
+        "str r0, [", $regs, ", #0]\n",
+        "str r3, [", $regs, ", #12]\n",
+        "str r4, [", $regs, ", #16]\n",
+        "str r5, [", $regs, ", #20]\n",
+        "str r6, [", $regs, ", #24]\n",
+        "str r7, [", $regs, ", #28]\n",
+        "str r8, [", $regs, ", #32]\n",
+        "str r9, [", $regs, ", #36]\n",
+        "str r10, [", $regs, ", #40]\n",
+        "str r11, [", $regs, ", #44]\n",
+        "str r12, [", $regs, ", #48]\n",
+        "str sp, [", $regs, ", #52]\n",
+        "str lr, [", $regs, ", #56]\n",
+    ))
+}
+
+// Restore r3-r12, sp and lr from $regs
+macro_rules! restore_registers {
+    ($regs:expr) => (concat!(
+        "ldr r3, [", $regs, ", #12]\n",
+        "ldr r4, [", $regs, ", #16]\n",
+        "ldr r5, [", $regs, ", #20]\n",
+        "ldr r6, [", $regs, ", #24]\n",
+        "ldr r7, [", $regs, ", #28]\n",
+        "ldr r8, [", $regs, ", #32]\n",
+        "ldr r9, [", $regs, ", #36]\n",
+        "ldr r10, [", $regs, ", #40]\n",
+        "ldr r11, [", $regs, ", #44]\n",
+        "ldr r12, [", $regs, ", #48]\n",
+        "ldr sp, [", $regs, ", #52]\n",
+        "ldr lr, [", $regs, ", #56]\n",
+    ))
+}
+
+global_asm!(concat!(
+    ".text\n",
+    ".code 32\n",
+    ".arm\n",
+    ".align 2\n",
+
+    // swap_registers(registers_t *oregs, registers_t *regs)
+    ".globl ", c_symbol!("rust_swap_registers"), "\n",
+    c_symbol!("rust_swap_registers"), ":\n",
+    save_registers!("r0"),
+    "mrs r2, cpsr\n",
+    "str r2, [r0, #64]\n",
+    restore_registers!("r1"),
+    "ldr r2, [r1, #64]\n",
+    "msr cpsr_cxsf, r2\n",
+    "mov r0, #0\n",
+    "mov pc, lr\n",
+
+    // load_registers(registers_t *regs)
+    ".globl ", c_symbol!("rust_load_registers"), "\n",
+    c_symbol!("rust_load_registers"), ":\n",
+    restore_registers!("r0"),
+    "ldr r2, [r0, #64]\n",
+    "msr cpsr_cxsf, r2\n",
+    "mov r0, #0\n",
+    "mov pc, lr\n",
+
+    // jump_registers(registers_t *oregs, registers_t *regs, transfer_t *t) -> transfer_t *
+    ".globl ", c_symbol!("rust_jump_registers"), "\n",
+    c_symbol!("rust_jump_registers"), ":\n",
+    save_registers!("r0"),
+    "mrs r3, cpsr\n",
+    "str r3, [r0, #64]\n",
+    "ldr r3, [r1, #64]\n",
+    "msr cpsr_cxsf, r3\n",
+    restore_registers!("r1"),
+    "mov r0, r2\n",
+    "mov pc, lr\n",
+
+    // ontop_registers(registers_t *oregs, registers_t *regs, transfer_t *t,
+    //                 transfer_t *(*fn)(transfer_t *)) -> transfer_t *
+    ".globl ", c_symbol!("rust_ontop_registers"), "\n",
+    c_symbol!("rust_ontop_registers"), ":\n",
+    save_registers!("r0"),
+    "mrs r12, cpsr\n",
+    "str r12, [r0, #64]\n",
+    "mov r0, r2\n",
+    "mov r2, r3\n",
+    "ldr r3, [r1, #64]\n",
+    "msr cpsr_cxsf, r3\n",
+    restore_registers!("r1"),
+    "mov pc, r2\n",
+
+    // The first code run by a new context
+    ".globl ", c_symbol!("rust_bootstrap_green_task"), "\n",
+    c_symbol!("rust_bootstrap_green_task"), ":\n",
+    "mov r2, r0\n",
+    "mov r0, r4\n",
+    "mov r1, r6\n",
+    "mov pc, r5\n",
+));
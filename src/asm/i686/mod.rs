@@ -0,0 +1,100 @@
+// The routines of _context.S next to this file, for the `global-asm` feature.
+// Keep both in sync, see there for the details and the comments.
+
+use std::arch::global_asm;
+
+// Save ebx, ebp, esi, edi, the flags, esp and the return address into $regs,
+// popping the latter off the stack
+macro_rules! save_registers {
+    ($regs:expr) => (concat!(
+        "movl %ebx, 4(", $regs, ")\n",
+        "movl %ebp, 16(", $regs, ")\n",
+        "movl %esi, 20(", $regs, ")\n",
+        "movl %edi, 24(", $regs, ")\n",
+        "pushf\n",
+        "popl %ecx\n",
+        "movl %ecx, 44(", $regs, ")\n",
+        "popl %ecx\n",
+        "movl %esp, 28(", $regs, ")\n",
+        "movl %ecx, 48(", $regs, ")\n",
+    ))
+}
+
+// Restore ebx, ebp, esi, edi, esp and the flags from $regs
+macro_rules! restore_registers {
+    ($regs:expr) => (concat!(
+        "movl 4(", $regs, "), %ebx\n",
+        "movl 16(", $regs, "), %ebp\n",
+        "movl 20(", $regs, "), %esi\n",
+        "movl 24(", $regs, "), %edi\n",
+        "movl 28(", $regs, "), %esp\n",
+        "pushl 44(", $regs, ")\n",
+        "popf\n",
+    ))
+}
+
+global_asm!(concat!(
+    ".text\n",
+
+    // swap_registers(registers_t *oregs, registers_t *regs)
+    ".globl ", c_symbol!("rust_swap_registers"), "\n",
+    c_symbol!("rust_swap_registers"), ":\n",
+    "movl 4(%esp), %eax\n",
+    save_registers!("%eax"),
+    "movl 4(%esp), %eax\n",
+    restore_registers!("%eax"),
+    "movl 48(%eax), %ecx\n",
+    "xorl %eax, %eax\n",
+    "jmp *%ecx\n",
+
+    // save_registers(registers_t *regs)
+    ".globl ", c_symbol!("rust_save_registers"), "\n",
+    c_symbol!("rust_save_registers"), ":\n",
+    "movl 4(%esp), %eax\n",
+    save_registers!("%eax"),
+    "jmp *48(%eax)\n",
+
+    // load_registers(registers_t *regs)
+    ".globl ", c_symbol!("rust_load_registers"), "\n",
+    c_symbol!("rust_load_registers"), ":\n",
+    "movl 4(%esp), %eax\n",
+    restore_registers!("%eax"),
+    "movl 48(%eax), %ecx\n",
+    "xorl %eax, %eax\n",
+    "jmp *%ecx\n",
+
+    // jump_registers(registers_t *oregs, registers_t *regs, transfer_t *t) -> transfer_t *
+    ".globl ", c_symbol!("rust_jump_registers"), "\n",
+    c_symbol!("rust_jump_registers"), ":\n",
+    "movl 4(%esp), %eax\n",
+    save_registers!("%eax"),
+    "movl 8(%esp), %edx\n",
+    "movl 4(%esp), %eax\n",
+    restore_registers!("%eax"),
+    "movl 48(%eax), %ecx\n",
+    "movl %edx, %eax\n",
+    "jmp *%ecx\n",
+
+    // ontop_registers(registers_t *oregs, registers_t *regs, transfer_t *t,
+    //                 transfer_t *(*fn)(transfer_t *)) -> transfer_t *
+    ".globl ", c_symbol!("rust_ontop_registers"), "\n",
+    c_symbol!("rust_ontop_registers"), ":\n",
+    "movl 4(%esp), %eax\n",
+    save_registers!("%eax"),
+    "movl 8(%esp), %edx\n",
+    "movl 12(%esp), %ecx\n",
+    "movl 4(%esp), %eax\n",
+    restore_registers!("%eax"),
+    "pushl 48(%eax)\n",
+    "subl $8, %esp\n",
+    "pushl %edx\n",
+    "call *%ecx\n",
+    "addl $12, %esp\n",
+    "ret\n",
+
+    // The first code run by a new context
+    ".globl ", c_symbol!("rust_bootstrap_green_task"), "\n",
+    c_symbol!("rust_bootstrap_green_task"), ":\n",
+    "movl %eax, 12(%esp)\n",
+    "jmp *%esi\n",
+), options(att_syntax));
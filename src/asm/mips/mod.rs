@@ -0,0 +1,216 @@
+// The routines of _context.S next to this file, for the `global-asm` feature.
+// Keep both in sync, see there for the details and the comments.
+
+use std::arch::global_asm;
+
+// Save $1-$31 into $regs
+macro_rules! save_registers {
+    ($regs:expr) => (concat!(
+        "sw $1, 1 * 4(", $regs, ")\n",
+        "sw $2, 2 * 4(", $regs, ")\n",
+        "sw $3, 3 * 4(", $regs, ")\n",
+        "sw $4, 4 * 4(", $regs, ")\n",
+        "sw $5, 5 * 4(", $regs, ")\n",
+        "sw $6, 6 * 4(", $regs, ")\n",
+        "sw $7, 7 * 4(", $regs, ")\n",
+        "sw $8, 8 * 4(", $regs, ")\n",
+        "sw $9, 9 * 4(", $regs, ")\n",
+        "sw $10, 10 * 4(", $regs, ")\n",
+        "sw $11, 11 * 4(", $regs, ")\n",
+        "sw $12, 12 * 4(", $regs, ")\n",
+        "sw $13, 13 * 4(", $regs, ")\n",
+        "sw $14, 14 * 4(", $regs, ")\n",
+        "sw $15, 15 * 4(", $regs, ")\n",
+        "sw $16, 16 * 4(", $regs, ")\n",
+        "sw $17, 17 * 4(", $regs, ")\n",
+        "sw $18, 18 * 4(", $regs, ")\n",
+        "sw $19, 19 * 4(", $regs, ")\n",
+        "sw $20, 20 * 4(", $regs, ")\n",
+        "sw $21, 21 * 4(", $regs, ")\n",
+        "sw $22, 22 * 4(", $regs, ")\n",
+        "sw $23, 23 * 4(", $regs, ")\n",
+        "sw $24, 24 * 4(", $regs, ")\n",
+        "sw $25, 25 * 4(", $regs, ")\n",
+        "sw $26, 26 * 4(", $regs, ")\n",
+        "sw $27, 27 * 4(", $regs, ")\n",
+        "sw $28, 28 * 4(", $regs, ")\n",
+        "sw $29, 29 * 4(", $regs, ")\n",
+        "sw $30, 30 * 4(", $regs, ")\n",
+        "sw $31, 31 * 4(", $regs, ")\n",
+    ))
+}
+
+// Restore $1-$31 from $regs, but $2 for the return value and the argument
+// registers $4 and $5, which $regs may well be
+macro_rules! restore_registers {
+    ($regs:expr) => (concat!(
+        "lw $1, 1 * 4(", $regs, ")\n",
+        "lw $3, 3 * 4(", $regs, ")\n",
+        "lw $6, 6 * 4(", $regs, ")\n",
+        "lw $7, 7 * 4(", $regs, ")\n",
+        "lw $8, 8 * 4(", $regs, ")\n",
+        "lw $9, 9 * 4(", $regs, ")\n",
+        "lw $10, 10 * 4(", $regs, ")\n",
+        "lw $11, 11 * 4(", $regs, ")\n",
+        "lw $12, 12 * 4(", $regs, ")\n",
+        "lw $13, 13 * 4(", $regs, ")\n",
+        "lw $14, 14 * 4(", $regs, ")\n",
+        "lw $15, 15 * 4(", $regs, ")\n",
+        "lw $16, 16 * 4(", $regs, ")\n",
+        "lw $17, 17 * 4(", $regs, ")\n",
+        "lw $18, 18 * 4(", $regs, ")\n",
+        "lw $19, 19 * 4(", $regs, ")\n",
+        "lw $20, 20 * 4(", $regs, ")\n",
+        "lw $21, 21 * 4(", $regs, ")\n",
+        "lw $22, 22 * 4(", $regs, ")\n",
+        "lw $23, 23 * 4(", $regs, ")\n",
+        "lw $24, 24 * 4(", $regs, ")\n",
+        "lw $25, 25 * 4(", $regs, ")\n",
+        "lw $26, 26 * 4(", $regs, ")\n",
+        "lw $27, 27 * 4(", $regs, ")\n",
+        "lw $28, 28 * 4(", $regs, ")\n",
+        "lw $29, 29 * 4(", $regs, ")\n",
+        "lw $30, 30 * 4(", $regs, ")\n",
+        "lw $31, 31 * 4(", $regs, ")\n",
+    ))
+}
+
+// Same as restore_registers, but leaves $25 alone as well
+macro_rules! restore_registers_ontop {
+    ($regs:expr) => (concat!(
+        "lw $1, 1 * 4(", $regs, ")\n",
+        "lw $3, 3 * 4(", $regs, ")\n",
+        "lw $6, 6 * 4(", $regs, ")\n",
+        "lw $7, 7 * 4(", $regs, ")\n",
+        "lw $8, 8 * 4(", $regs, ")\n",
+        "lw $9, 9 * 4(", $regs, ")\n",
+        "lw $10, 10 * 4(", $regs, ")\n",
+        "lw $11, 11 * 4(", $regs, ")\n",
+        "lw $12, 12 * 4(", $regs, ")\n",
+        "lw $13, 13 * 4(", $regs, ")\n",
+        "lw $14, 14 * 4(", $regs, ")\n",
+        "lw $15, 15 * 4(", $regs, ")\n",
+        "lw $16, 16 * 4(", $regs, ")\n",
+        "lw $17, 17 * 4(", $regs, ")\n",
+        "lw $18, 18 * 4(", $regs, ")\n",
+        "lw $19, 19 * 4(", $regs, ")\n",
+        "lw $20, 20 * 4(", $regs, ")\n",
+        "lw $21, 21 * 4(", $regs, ")\n",
+        "lw $22, 22 * 4(", $regs, ")\n",
+        "lw $23, 23 * 4(", $regs, ")\n",
+        "lw $24, 24 * 4(", $regs, ")\n",
+        "lw $26, 26 * 4(", $regs, ")\n",
+        "lw $27, 27 * 4(", $regs, ")\n",
+        "lw $28, 28 * 4(", $regs, ")\n",
+        "lw $29, 29 * 4(", $regs, ")\n",
+        "lw $30, 30 * 4(", $regs, ")\n",
+        "lw $31, 31 * 4(", $regs, ")\n",
+    ))
+}
+
+global_asm!(concat!(
+    ".text\n",
+
+    // swap_registers(registers_t *oregs, registers_t *regs)
+    ".globl rust_swap_registers\n",
+    ".align 2\n",
+    ".set nomips16\n",
+    ".ent rust_swap_registers\n",
+    "rust_swap_registers:\n",
+    ".set noreorder\n",
+    ".set nomacro\n",
+    ".set noat\n",
+    save_registers!("$4"),
+    restore_registers!("$5"),
+    "lw $4, 4 * 4($5)\n",
+    "lw $5, 5 * 4($5)\n",
+    "move $2, $0\n",
+    "jr $31\n",
+    "nop\n",
+    ".end rust_swap_registers\n",
+
+    // save_registers(registers_t *regs)
+    ".globl rust_save_registers\n",
+    ".align 2\n",
+    ".set nomips16\n",
+    ".ent rust_save_registers\n",
+    "rust_save_registers:\n",
+    ".set noreorder\n",
+    ".set nomacro\n",
+    ".set noat\n",
+    save_registers!("$4"),
+    "jr $31\n",
+    "nop\n",
+    ".end rust_save_registers\n",
+
+    // load_registers(registers_t *regs)
+    ".globl rust_load_registers\n",
+    ".align 2\n",
+    ".set nomips16\n",
+    ".ent rust_load_registers\n",
+    "rust_load_registers:\n",
+    ".set noreorder\n",
+    ".set nomacro\n",
+    ".set noat\n",
+    restore_registers!("$4"),
+    "lw $5, 5 * 4($4)\n",
+    "lw $4, 4 * 4($4)\n",
+    "move $2, $0\n",
+    "jr $31\n",
+    "nop\n",
+    ".end rust_load_registers\n",
+
+    // jump_registers(registers_t *oregs, registers_t *regs, transfer_t *t) -> transfer_t *
+    ".globl rust_jump_registers\n",
+    ".align 2\n",
+    ".set nomips16\n",
+    ".ent rust_jump_registers\n",
+    "rust_jump_registers:\n",
+    ".set noreorder\n",
+    ".set nomacro\n",
+    ".set noat\n",
+    save_registers!("$4"),
+    "move $2, $6\n",
+    restore_registers!("$5"),
+    "lw $4, 4 * 4($5)\n",
+    "lw $5, 5 * 4($5)\n",
+    "jr $31\n",
+    "nop\n",
+    ".end rust_jump_registers\n",
+
+    // ontop_registers(registers_t *oregs, registers_t *regs, transfer_t *t,
+    //                 transfer_t *(*fn)(transfer_t *)) -> transfer_t *
+    ".globl rust_ontop_registers\n",
+    ".align 2\n",
+    ".set nomips16\n",
+    ".ent rust_ontop_registers\n",
+    "rust_ontop_registers:\n",
+    ".set noreorder\n",
+    ".set nomacro\n",
+    ".set noat\n",
+    save_registers!("$4"),
+    "move $4, $6\n",
+    "move $25, $7\n",
+    restore_registers_ontop!("$5"),
+    "lw $5, 5 * 4($5)\n",
+    "jr $25\n",
+    "nop\n",
+    ".end rust_ontop_registers\n",
+
+    // The first code run by a new context
+    ".globl rust_bootstrap_green_task\n",
+    ".align 2\n",
+    ".set nomips16\n",
+    ".ent rust_bootstrap_green_task\n",
+    "rust_bootstrap_green_task:\n",
+    ".set noreorder\n",
+    ".set nomacro\n",
+    ".set noat\n",
+    "move $4, $16\n",
+    "move $5, $17\n",
+    "move $6, $2\n",
+    "move $25, $18\n",
+    "jr $25\n",
+    "nop\n",
+    ".end rust_bootstrap_green_task\n",
+));
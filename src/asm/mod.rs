@@ -0,0 +1,31 @@
+// The context switching routines as `global_asm!`, built instead of the
+// _context.S files when the `global-asm` feature is enabled, so that no C
+// toolchain is needed.
+
+// The name a C symbol gets in assembly on the target
+#[cfg(not(any(target_os = "macos", target_os = "ios",
+              all(windows, target_arch = "x86"))))]
+macro_rules! c_symbol {
+    ($name:expr) => ($name)
+}
+#[cfg(any(target_os = "macos", target_os = "ios",
+          all(windows, target_arch = "x86")))]
+macro_rules! c_symbol {
+    ($name:expr) => (concat!("_", $name))
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86_64;
+#[cfg(target_arch = "x86")]
+mod i686;
+#[cfg(target_arch = "arm")]
+mod arm;
+#[cfg(target_arch = "aarch64")]
+mod aarch64;
+#[cfg(all(target_arch = "powerpc64", target_endian = "little"))]
+mod powerpc64le;
+#[cfg(target_arch = "riscv64")]
+mod riscv64;
+// mips and mipsel share the code
+#[cfg(target_arch = "mips")]
+mod mips;
@@ -0,0 +1,215 @@
+// The routines of _context.S next to this file, for the `global-asm` feature.
+// Keep both in sync, see there for the details and the comments.
+
+use std::arch::global_asm;
+
+// Save r1, r2, LR, CR, r14-r31, f14-f31 and v20-v31 into $regs
+macro_rules! save_registers {
+    ($regs:expr) => (concat!(
+        "std %r1, 0(", $regs, ")\n",
+        "std %r2, 8(", $regs, ")\n",
+        "mflr %r0\n",
+        "std %r0, 16(", $regs, ")\n",
+        "mfcr %r0\n",
+        "std %r0, 24(", $regs, ")\n",
+        "std %r14, 32(", $regs, ")\n",
+        "std %r15, 40(", $regs, ")\n",
+        "std %r16, 48(", $regs, ")\n",
+        "std %r17, 56(", $regs, ")\n",
+        "std %r18, 64(", $regs, ")\n",
+        "std %r19, 72(", $regs, ")\n",
+        "std %r20, 80(", $regs, ")\n",
+        "std %r21, 88(", $regs, ")\n",
+        "std %r22, 96(", $regs, ")\n",
+        "std %r23, 104(", $regs, ")\n",
+        "std %r24, 112(", $regs, ")\n",
+        "std %r25, 120(", $regs, ")\n",
+        "std %r26, 128(", $regs, ")\n",
+        "std %r27, 136(", $regs, ")\n",
+        "std %r28, 144(", $regs, ")\n",
+        "std %r29, 152(", $regs, ")\n",
+        "std %r30, 160(", $regs, ")\n",
+        "std %r31, 168(", $regs, ")\n",
+        "stfd %f14, 176(", $regs, ")\n",
+        "stfd %f15, 184(", $regs, ")\n",
+        "stfd %f16, 192(", $regs, ")\n",
+        "stfd %f17, 200(", $regs, ")\n",
+        "stfd %f18, 208(", $regs, ")\n",
+        "stfd %f19, 216(", $regs, ")\n",
+        "stfd %f20, 224(", $regs, ")\n",
+        "stfd %f21, 232(", $regs, ")\n",
+        "stfd %f22, 240(", $regs, ")\n",
+        "stfd %f23, 248(", $regs, ")\n",
+        "stfd %f24, 256(", $regs, ")\n",
+        "stfd %f25, 264(", $regs, ")\n",
+        "stfd %f26, 272(", $regs, ")\n",
+        "stfd %f27, 280(", $regs, ")\n",
+        "stfd %f28, 288(", $regs, ")\n",
+        "stfd %f29, 296(", $regs, ")\n",
+        "stfd %f30, 304(", $regs, ")\n",
+        "stfd %f31, 312(", $regs, ")\n",
+        "li %r9, 320\n",
+        "stxvd2x %vs52, ", $regs, ", %r9\n",
+        "li %r9, 336\n",
+        "stxvd2x %vs53, ", $regs, ", %r9\n",
+        "li %r9, 352\n",
+        "stxvd2x %vs54, ", $regs, ", %r9\n",
+        "li %r9, 368\n",
+        "stxvd2x %vs55, ", $regs, ", %r9\n",
+        "li %r9, 384\n",
+        "stxvd2x %vs56, ", $regs, ", %r9\n",
+        "li %r9, 400\n",
+        "stxvd2x %vs57, ", $regs, ", %r9\n",
+        "li %r9, 416\n",
+        "stxvd2x %vs58, ", $regs, ", %r9\n",
+        "li %r9, 432\n",
+        "stxvd2x %vs59, ", $regs, ", %r9\n",
+        "li %r9, 448\n",
+        "stxvd2x %vs60, ", $regs, ", %r9\n",
+        "li %r9, 464\n",
+        "stxvd2x %vs61, ", $regs, ", %r9\n",
+        "li %r9, 480\n",
+        "stxvd2x %vs62, ", $regs, ", %r9\n",
+        "li %r9, 496\n",
+        "stxvd2x %vs63, ", $regs, ", %r9\n",
+    ))
+}
+
+// Restore r1, r2, LR, CR, r14-r31, f14-f31 and v20-v31 from $regs
+macro_rules! restore_registers {
+    ($regs:expr) => (concat!(
+        "li %r9, 320\n",
+        "lxvd2x %vs52, ", $regs, ", %r9\n",
+        "li %r9, 336\n",
+        "lxvd2x %vs53, ", $regs, ", %r9\n",
+        "li %r9, 352\n",
+        "lxvd2x %vs54, ", $regs, ", %r9\n",
+        "li %r9, 368\n",
+        "lxvd2x %vs55, ", $regs, ", %r9\n",
+        "li %r9, 384\n",
+        "lxvd2x %vs56, ", $regs, ", %r9\n",
+        "li %r9, 400\n",
+        "lxvd2x %vs57, ", $regs, ", %r9\n",
+        "li %r9, 416\n",
+        "lxvd2x %vs58, ", $regs, ", %r9\n",
+        "li %r9, 432\n",
+        "lxvd2x %vs59, ", $regs, ", %r9\n",
+        "li %r9, 448\n",
+        "lxvd2x %vs60, ", $regs, ", %r9\n",
+        "li %r9, 464\n",
+        "lxvd2x %vs61, ", $regs, ", %r9\n",
+        "li %r9, 480\n",
+        "lxvd2x %vs62, ", $regs, ", %r9\n",
+        "li %r9, 496\n",
+        "lxvd2x %vs63, ", $regs, ", %r9\n",
+        "lfd %f14, 176(", $regs, ")\n",
+        "lfd %f15, 184(", $regs, ")\n",
+        "lfd %f16, 192(", $regs, ")\n",
+        "lfd %f17, 200(", $regs, ")\n",
+        "lfd %f18, 208(", $regs, ")\n",
+        "lfd %f19, 216(", $regs, ")\n",
+        "lfd %f20, 224(", $regs, ")\n",
+        "lfd %f21, 232(", $regs, ")\n",
+        "lfd %f22, 240(", $regs, ")\n",
+        "lfd %f23, 248(", $regs, ")\n",
+        "lfd %f24, 256(", $regs, ")\n",
+        "lfd %f25, 264(", $regs, ")\n",
+        "lfd %f26, 272(", $regs, ")\n",
+        "lfd %f27, 280(", $regs, ")\n",
+        "lfd %f28, 288(", $regs, ")\n",
+        "lfd %f29, 296(", $regs, ")\n",
+        "lfd %f30, 304(", $regs, ")\n",
+        "lfd %f31, 312(", $regs, ")\n",
+        "ld %r14, 32(", $regs, ")\n",
+        "ld %r15, 40(", $regs, ")\n",
+        "ld %r16, 48(", $regs, ")\n",
+        "ld %r17, 56(", $regs, ")\n",
+        "ld %r18, 64(", $regs, ")\n",
+        "ld %r19, 72(", $regs, ")\n",
+        "ld %r20, 80(", $regs, ")\n",
+        "ld %r21, 88(", $regs, ")\n",
+        "ld %r22, 96(", $regs, ")\n",
+        "ld %r23, 104(", $regs, ")\n",
+        "ld %r24, 112(", $regs, ")\n",
+        "ld %r25, 120(", $regs, ")\n",
+        "ld %r26, 128(", $regs, ")\n",
+        "ld %r27, 136(", $regs, ")\n",
+        "ld %r28, 144(", $regs, ")\n",
+        "ld %r29, 152(", $regs, ")\n",
+        "ld %r30, 160(", $regs, ")\n",
+        "ld %r31, 168(", $regs, ")\n",
+        "ld %r0, 24(", $regs, ")\n",
+        "mtcr %r0\n",
+        "ld %r0, 16(", $regs, ")\n",
+        "mtlr %r0\n",
+        "ld %r2, 8(", $regs, ")\n",
+        "ld %r1, 0(", $regs, ")\n",
+    ))
+}
+
+global_asm!(concat!(
+    ".abiversion 2\n",
+    ".text\n",
+    ".align 2\n",
+
+    // swap_registers(registers_t *oregs, registers_t *regs)
+    ".globl rust_swap_registers\n",
+    "rust_swap_registers:\n",
+    save_registers!("%r3"),
+    restore_registers!("%r4"),
+    "li %r3, 0\n",
+    "blr\n",
+
+    // save_registers(registers_t *regs)
+    ".globl rust_save_registers\n",
+    "rust_save_registers:\n",
+    save_registers!("%r3"),
+    "blr\n",
+
+    // load_registers(registers_t *regs)
+    ".globl rust_load_registers\n",
+    "rust_load_registers:\n",
+    restore_registers!("%r3"),
+    "li %r3, 0\n",
+    "blr\n",
+
+    // jump_registers(registers_t *oregs, registers_t *regs, transfer_t *t) -> transfer_t *
+    ".globl rust_jump_registers\n",
+    "rust_jump_registers:\n",
+    save_registers!("%r3"),
+    restore_registers!("%r4"),
+    "mr %r3, %r5\n",
+    "blr\n",
+
+    // ontop_registers(registers_t *oregs, registers_t *regs, transfer_t *t,
+    //                 transfer_t *(*fn)(transfer_t *)) -> transfer_t *
+    ".globl rust_ontop_registers\n",
+    "rust_ontop_registers:\n",
+    save_registers!("%r3"),
+    restore_registers!("%r4"),
+    "std %r2, 24(%r1)\n",
+    "mflr %r0\n",
+    "std %r0, 16(%r1)\n",
+    "stdu %r1, -32(%r1)\n",
+    "mr %r3, %r5\n",
+    "mr %r12, %r6\n",
+    "mtctr %r12\n",
+    "bctrl\n",
+    "addi %r1, %r1, 32\n",
+    "ld %r2, 24(%r1)\n",
+    "ld %r0, 16(%r1)\n",
+    "mtlr %r0\n",
+    "blr\n",
+
+    // The first code run by a new context
+    ".globl rust_bootstrap_green_task\n",
+    "rust_bootstrap_green_task:\n",
+    "mr %r5, %r3\n",
+    "mr %r3, %r14\n",
+    "mr %r4, %r15\n",
+    "mr %r12, %r16\n",
+    "mtctr %r12\n",
+    "li %r0, 0\n",
+    "mtlr %r0\n",
+    "bctr\n",
+));
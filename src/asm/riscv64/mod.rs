@@ -0,0 +1,147 @@
+// The routines of _context.S next to this file, for the `global-asm` feature.
+// Keep both in sync, see there for the details and the comments.
+
+use std::arch::global_asm;
+
+// Save ra, sp and s0-s11 into $regs
+macro_rules! save_registers {
+    ($regs:expr) => (concat!(
+        "sd ra, 0(", $regs, ")\n",
+        "sd sp, 8(", $regs, ")\n",
+        "sd s0, 16(", $regs, ")\n",
+        "sd s1, 24(", $regs, ")\n",
+        "sd s2, 32(", $regs, ")\n",
+        "sd s3, 40(", $regs, ")\n",
+        "sd s4, 48(", $regs, ")\n",
+        "sd s5, 56(", $regs, ")\n",
+        "sd s6, 64(", $regs, ")\n",
+        "sd s7, 72(", $regs, ")\n",
+        "sd s8, 80(", $regs, ")\n",
+        "sd s9, 88(", $regs, ")\n",
+        "sd s10, 96(", $regs, ")\n",
+        "sd s11, 104(", $regs, ")\n",
+    ))
+}
+
+// Restore ra, sp and s0-s11 from $regs
+macro_rules! restore_registers {
+    ($regs:expr) => (concat!(
+        "ld ra, 0(", $regs, ")\n",
+        "ld sp, 8(", $regs, ")\n",
+        "ld s0, 16(", $regs, ")\n",
+        "ld s1, 24(", $regs, ")\n",
+        "ld s2, 32(", $regs, ")\n",
+        "ld s3, 40(", $regs, ")\n",
+        "ld s4, 48(", $regs, ")\n",
+        "ld s5, 56(", $regs, ")\n",
+        "ld s6, 64(", $regs, ")\n",
+        "ld s7, 72(", $regs, ")\n",
+        "ld s8, 80(", $regs, ")\n",
+        "ld s9, 88(", $regs, ")\n",
+        "ld s10, 96(", $regs, ")\n",
+        "ld s11, 104(", $regs, ")\n",
+    ))
+}
+
+// Save fs0-fs11 into $regs
+#[cfg(target_feature = "d")]
+macro_rules! save_fp_registers {
+    ($regs:expr) => (concat!(
+        "fsd fs0, 112(", $regs, ")\n",
+        "fsd fs1, 120(", $regs, ")\n",
+        "fsd fs2, 128(", $regs, ")\n",
+        "fsd fs3, 136(", $regs, ")\n",
+        "fsd fs4, 144(", $regs, ")\n",
+        "fsd fs5, 152(", $regs, ")\n",
+        "fsd fs6, 160(", $regs, ")\n",
+        "fsd fs7, 168(", $regs, ")\n",
+        "fsd fs8, 176(", $regs, ")\n",
+        "fsd fs9, 184(", $regs, ")\n",
+        "fsd fs10, 192(", $regs, ")\n",
+        "fsd fs11, 200(", $regs, ")\n",
+    ))
+}
+
+// Restore fs0-fs11 from $regs
+#[cfg(target_feature = "d")]
+macro_rules! restore_fp_registers {
+    ($regs:expr) => (concat!(
+        "fld fs0, 112(", $regs, ")\n",
+        "fld fs1, 120(", $regs, ")\n",
+        "fld fs2, 128(", $regs, ")\n",
+        "fld fs3, 136(", $regs, ")\n",
+        "fld fs4, 144(", $regs, ")\n",
+        "fld fs5, 152(", $regs, ")\n",
+        "fld fs6, 160(", $regs, ")\n",
+        "fld fs7, 168(", $regs, ")\n",
+        "fld fs8, 176(", $regs, ")\n",
+        "fld fs9, 184(", $regs, ")\n",
+        "fld fs10, 192(", $regs, ")\n",
+        "fld fs11, 200(", $regs, ")\n",
+    ))
+}
+
+#[cfg(not(target_feature = "d"))]
+macro_rules! save_fp_registers { ($regs:expr) => ("") }
+#[cfg(not(target_feature = "d"))]
+macro_rules! restore_fp_registers { ($regs:expr) => ("") }
+
+global_asm!(concat!(
+    ".text\n",
+    ".align 2\n",
+
+    // swap_registers(registers_t *oregs, registers_t *regs)
+    ".globl rust_swap_registers\n",
+    "rust_swap_registers:\n",
+    save_registers!("a0"),
+    save_fp_registers!("a0"),
+    restore_registers!("a1"),
+    restore_fp_registers!("a1"),
+    "li a0, 0\n",
+    "ret\n",
+
+    // save_registers(registers_t *regs)
+    ".globl rust_save_registers\n",
+    "rust_save_registers:\n",
+    save_registers!("a0"),
+    save_fp_registers!("a0"),
+    "ret\n",
+
+    // load_registers(registers_t *regs)
+    ".globl rust_load_registers\n",
+    "rust_load_registers:\n",
+    restore_registers!("a0"),
+    restore_fp_registers!("a0"),
+    "li a0, 0\n",
+    "ret\n",
+
+    // jump_registers(registers_t *oregs, registers_t *regs, transfer_t *t) -> transfer_t *
+    ".globl rust_jump_registers\n",
+    "rust_jump_registers:\n",
+    save_registers!("a0"),
+    save_fp_registers!("a0"),
+    restore_registers!("a1"),
+    restore_fp_registers!("a1"),
+    "mv a0, a2\n",
+    "ret\n",
+
+    // ontop_registers(registers_t *oregs, registers_t *regs, transfer_t *t,
+    //                 transfer_t *(*fn)(transfer_t *)) -> transfer_t *
+    ".globl rust_ontop_registers\n",
+    "rust_ontop_registers:\n",
+    save_registers!("a0"),
+    save_fp_registers!("a0"),
+    restore_registers!("a1"),
+    restore_fp_registers!("a1"),
+    "mv a0, a2\n",
+    "jr a3\n",
+
+    // The first code run by a new context
+    ".globl rust_bootstrap_green_task\n",
+    "rust_bootstrap_green_task:\n",
+    "mv a2, a0\n",
+    "mv a0, s1\n",
+    "mv a1, s2\n",
+    "li ra, 0\n",
+    "jr s3\n",
+));
@@ -0,0 +1,209 @@
+/*
+The context switching routines on top of getcontext/makecontext/swapcontext,
+built instead of the _context.S files when the `ucontext` feature is enabled.
+
+It is a lot slower, as every switch saves and restores the signal mask, but
+works on any target with a ucontext implementation. musl has none, link
+libucontext's POSIX compatibility library there.
+
+registers_t is a ucontext_t here. It is kept in `Registers` in src/context.rs,
+which is at least REGISTERS_SIZE bytes large and 16-byte aligned.
+rust_save_registers is getcontext itself, it can't be wrapped, as the saved
+context would return into a function which has already returned.
+*/
+
+#define _GNU_SOURCE
+#include <signal.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <ucontext.h>
+
+#define REGISTERS_SIZE (640 * sizeof(uintptr_t))
+
+_Static_assert(sizeof(ucontext_t) <= REGISTERS_SIZE,
+               "ucontext_t doesn't fit into Registers");
+_Static_assert(_Alignof(ucontext_t) <= 16,
+               "ucontext_t is aligned stricter than Registers");
+
+typedef struct transfer transfer_t;
+typedef transfer_t *(*ontop_fn)(transfer_t *);
+typedef void (*init_fn)(uintptr_t, void *, transfer_t *);
+
+// What is handed over to the context being resumed, and the function to run
+// on top of it first. Both are only set between the switch and the resumed
+// side picking them up, which never crosses a thread.
+static __thread transfer_t *handed_over = NULL;
+static __thread ontop_fn on_top = NULL;
+
+// Called by every routine right after it has been resumed
+static transfer_t *take_transfer(void) {
+    transfer_t *t = handed_over;
+    ontop_fn f = on_top;
+
+    handed_over = NULL;
+    on_top = NULL;
+    return f ? f(t) : t;
+}
+
+// glibc keeps pointers into the ucontext_t itself, to where the FPU state
+// or the vector registers are saved, and loads them from there. They are
+// stale once the Context holding it has been moved, e.g. returned by value
+// from Context::new, so point them at this copy before every load.
+static void fix_pointers(ucontext_t *regs) {
+#if defined(__GLIBC__) && (defined(__x86_64__) || defined(__i386__))
+    regs->uc_mcontext.fpregs = &regs->__fpregs_mem;
+#elif defined(__GLIBC__) && defined(__powerpc64__)
+    regs->uc_mcontext.v_regs = (void *)
+        (((uintptr_t) regs->uc_mcontext.vmx_reserve + 15) & ~(uintptr_t) 15);
+#else
+    (void) regs;
+#endif
+}
+
+// swap_registers(registers_t *oregs, registers_t *regs)
+void rust_swap_registers(ucontext_t *oregs, ucontext_t *regs) {
+    fix_pointers(regs);
+    swapcontext(oregs, regs);
+    take_transfer();
+}
+
+// load_registers(registers_t *regs)
+void rust_load_registers(ucontext_t *regs) {
+    fix_pointers(regs);
+    setcontext(regs);
+}
+
+// jump_registers(registers_t *oregs, registers_t *regs, transfer_t *t) -> transfer_t *
+transfer_t *rust_jump_registers(ucontext_t *oregs, ucontext_t *regs,
+                                transfer_t *t) {
+    handed_over = t;
+    fix_pointers(regs);
+    swapcontext(oregs, regs);
+    return take_transfer();
+}
+
+// ontop_registers(registers_t *oregs, registers_t *regs, transfer_t *t,
+//                 transfer_t *(*fn)(transfer_t *)) -> transfer_t *
+transfer_t *rust_ontop_registers(ucontext_t *oregs, ucontext_t *regs,
+                                 transfer_t *t, ontop_fn f) {
+    handed_over = t;
+    on_top = f;
+    fix_pointers(regs);
+    swapcontext(oregs, regs);
+    return take_transfer();
+}
+
+// registers_sp(registers_t *regs) -> uintptr_t
+//
+// The stack pointer saved in regs, 0 where the layout of mcontext_t isn't
+// known, and the same goes for the instruction and frame pointer below
+uintptr_t rust_registers_sp(const ucontext_t *regs) {
+#if defined(__linux__) && defined(__x86_64__)
+    return regs->uc_mcontext.gregs[REG_RSP];
+#elif defined(__linux__) && defined(__i386__)
+    return regs->uc_mcontext.gregs[REG_ESP];
+#elif defined(__linux__) && defined(__aarch64__)
+    return regs->uc_mcontext.sp;
+#elif defined(__linux__) && defined(__arm__)
+    return regs->uc_mcontext.arm_sp;
+#elif defined(__linux__) && defined(__riscv)
+    return regs->uc_mcontext.__gregs[REG_SP];
+#elif defined(__linux__) && defined(__powerpc64__)
+    return regs->uc_mcontext.gp_regs[1];
+#elif defined(__linux__) && defined(__mips__)
+    return regs->uc_mcontext.gregs[29];
+#else
+    (void) regs;
+    return 0;
+#endif
+}
+
+// registers_ip(registers_t *regs) -> uintptr_t
+uintptr_t rust_registers_ip(const ucontext_t *regs) {
+#if defined(__linux__) && defined(__x86_64__)
+    return regs->uc_mcontext.gregs[REG_RIP];
+#elif defined(__linux__) && defined(__i386__)
+    return regs->uc_mcontext.gregs[REG_EIP];
+#elif defined(__linux__) && defined(__aarch64__)
+    return regs->uc_mcontext.pc;
+#elif defined(__linux__) && defined(__arm__)
+    return regs->uc_mcontext.arm_pc;
+#elif defined(__linux__) && defined(__riscv)
+    return regs->uc_mcontext.__gregs[REG_PC];
+#elif defined(__linux__) && defined(__powerpc64__)
+    return regs->uc_mcontext.gp_regs[32];
+#elif defined(__linux__) && defined(__mips__)
+    return regs->uc_mcontext.pc;
+#else
+    (void) regs;
+    return 0;
+#endif
+}
+
+// registers_fp(registers_t *regs) -> uintptr_t
+uintptr_t rust_registers_fp(const ucontext_t *regs) {
+#if defined(__linux__) && defined(__x86_64__)
+    return regs->uc_mcontext.gregs[REG_RBP];
+#elif defined(__linux__) && defined(__i386__)
+    return regs->uc_mcontext.gregs[REG_EBP];
+#elif defined(__linux__) && defined(__aarch64__)
+    return regs->uc_mcontext.regs[29];
+#elif defined(__linux__) && defined(__arm__) && defined(__thumb__)
+    return regs->uc_mcontext.arm_r7;
+#elif defined(__linux__) && defined(__arm__)
+    return regs->uc_mcontext.arm_fp;
+#elif defined(__linux__) && defined(__riscv)
+    return regs->uc_mcontext.__gregs[REG_S0];
+#elif defined(__linux__) && defined(__powerpc64__)
+    return regs->uc_mcontext.gp_regs[31];
+#elif defined(__linux__) && defined(__mips__)
+    return regs->uc_mcontext.gregs[30];
+#else
+    (void) regs;
+    return 0;
+#endif
+}
+
+// The init function and its first two arguments, put on top of a new stack
+struct start {
+    init_fn fptr;
+    uintptr_t arg;
+    void *thunk;
+};
+
+// The first code run by a new context. makecontext only passes ints along,
+// so the pointer to the start struct comes in two halves.
+static void bootstrap(unsigned int hi, unsigned int lo) {
+    struct start *start = (struct start *) (((uint64_t) hi << 32) | lo);
+
+    start->fptr(start->arg, start->thunk, take_transfer());
+}
+
+// make_registers(registers_t *regs, init_fn fptr, uintptr_t arg, void *thunk,
+//                void *sp, uintptr_t stack_lo)
+void rust_make_registers(ucontext_t *regs, init_fn fptr, uintptr_t arg,
+                         void *thunk, void *sp, uintptr_t stack_lo) {
+    struct start *start = (struct start *)
+        (((uintptr_t) sp - sizeof(struct start)) & ~(uintptr_t) 15);
+    uint64_t p = (uintptr_t) start;
+
+    start->fptr = fptr;
+    start->arg = arg;
+    start->thunk = thunk;
+
+    // The stack of Context::from_raw_parts has got no known lower end,
+    // claim the least a signal handler runs on, makecontext only sets up a
+    // frame at the top
+    if (stack_lo == 0 || stack_lo >= (uintptr_t) start) {
+        stack_lo = (uintptr_t) start - MINSIGSTKSZ;
+    }
+
+    getcontext(regs);
+    // Everything below the start struct is free to use
+    regs->uc_stack.ss_sp = (void *) stack_lo;
+    regs->uc_stack.ss_size = (uintptr_t) start - stack_lo;
+    // The init function never returns
+    regs->uc_link = NULL;
+    makecontext(regs, (void (*)(void)) bootstrap, 2,
+                (unsigned int) (p >> 32), (unsigned int) p);
+}
@@ -0,0 +1,168 @@
+// The routines of _context.S next to this file, for the `global-asm` feature.
+// Keep both in sync, see there for the details and the comments.
+
+use std::arch::global_asm;
+
+// Save non-volatile registers (including RSP), the MXCSR, the x87 control word
+// and the 0th argument register into $regs
+#[cfg(not(windows))]
+macro_rules! save_registers {
+    ($regs:expr) => (concat!(
+        "mov %rbx, (0*8)(", $regs, ")\n",
+        "mov %rsp, (1*8)(", $regs, ")\n",
+        "mov %rbp, (2*8)(", $regs, ")\n",
+        "mov %r12, (4*8)(", $regs, ")\n",
+        "mov %r13, (5*8)(", $regs, ")\n",
+        "mov %r14, (6*8)(", $regs, ")\n",
+        "mov %r15, (7*8)(", $regs, ")\n",
+        "mov %rdi, (3*8)(", $regs, ")\n",
+        "stmxcsr (9*8)(", $regs, ")\n",
+        "fnstcw (9*8+4)(", $regs, ")\n",
+    ))
+}
+
+// Restore non-volatile registers (including RSP), the MXCSR and the x87 control
+// word from $regs, but the 0th argument register, which $regs may well be
+#[cfg(not(windows))]
+macro_rules! restore_registers {
+    ($regs:expr) => (concat!(
+        "mov (0*8)(", $regs, "), %rbx\n",
+        "mov (1*8)(", $regs, "), %rsp\n",
+        "mov (2*8)(", $regs, "), %rbp\n",
+        "mov (4*8)(", $regs, "), %r12\n",
+        "mov (5*8)(", $regs, "), %r13\n",
+        "mov (6*8)(", $regs, "), %r14\n",
+        "mov (7*8)(", $regs, "), %r15\n",
+        "ldmxcsr (9*8)(", $regs, ")\n",
+        "fldcw (9*8+4)(", $regs, ")\n",
+    ))
+}
+
+// Save non-volatile registers (including RSP) and the 0th argument register
+// into $regs
+#[cfg(windows)]
+macro_rules! save_registers {
+    ($regs:expr) => (concat!(
+        "mov %rbx, (0*8)(", $regs, ")\n",
+        "mov %rsp, (1*8)(", $regs, ")\n",
+        "mov %rbp, (2*8)(", $regs, ")\n",
+        "mov %r12, (4*8)(", $regs, ")\n",
+        "mov %r13, (5*8)(", $regs, ")\n",
+        "mov %r14, (6*8)(", $regs, ")\n",
+        "mov %r15, (7*8)(", $regs, ")\n",
+        "mov %rdi, (9*8)(", $regs, ")\n",
+        "mov %rsi, (10*8)(", $regs, ")\n",
+        "mov %rcx, (3*8)(", $regs, ")\n",
+        "movapd %xmm6, (14*8)(", $regs, ")\n",
+        "movapd %xmm7, (16*8)(", $regs, ")\n",
+        "movapd %xmm8, (18*8)(", $regs, ")\n",
+        "movapd %xmm9, (20*8)(", $regs, ")\n",
+        "movapd %xmm10, (22*8)(", $regs, ")\n",
+        "movapd %xmm11, (24*8)(", $regs, ")\n",
+        "movapd %xmm12, (26*8)(", $regs, ")\n",
+        "movapd %xmm13, (28*8)(", $regs, ")\n",
+        "movapd %xmm14, (30*8)(", $regs, ")\n",
+        "movapd %xmm15, (32*8)(", $regs, ")\n",
+    ))
+}
+
+// Restore non-volatile registers (including RSP) from $regs, but the 0th
+// argument register, which $regs may well be
+#[cfg(windows)]
+macro_rules! restore_registers {
+    ($regs:expr) => (concat!(
+        "mov (0*8)(", $regs, "), %rbx\n",
+        "mov (1*8)(", $regs, "), %rsp\n",
+        "mov (2*8)(", $regs, "), %rbp\n",
+        "mov (4*8)(", $regs, "), %r12\n",
+        "mov (5*8)(", $regs, "), %r13\n",
+        "mov (6*8)(", $regs, "), %r14\n",
+        "mov (7*8)(", $regs, "), %r15\n",
+        "mov (9*8)(", $regs, "), %rdi\n",
+        "mov (10*8)(", $regs, "), %rsi\n",
+        "movapd (14*8)(", $regs, "), %xmm6\n",
+        "movapd (16*8)(", $regs, "), %xmm7\n",
+        "movapd (18*8)(", $regs, "), %xmm8\n",
+        "movapd (20*8)(", $regs, "), %xmm9\n",
+        "movapd (22*8)(", $regs, "), %xmm10\n",
+        "movapd (24*8)(", $regs, "), %xmm11\n",
+        "movapd (26*8)(", $regs, "), %xmm12\n",
+        "movapd (28*8)(", $regs, "), %xmm13\n",
+        "movapd (30*8)(", $regs, "), %xmm14\n",
+        "movapd (32*8)(", $regs, "), %xmm15\n",
+    ))
+}
+
+// The registers arguments go in, see regs.h
+#[cfg(not(windows))] macro_rules! arg0 { () => ("%rdi") }
+#[cfg(not(windows))] macro_rules! arg1 { () => ("%rsi") }
+#[cfg(not(windows))] macro_rules! arg2 { () => ("%rdx") }
+#[cfg(not(windows))] macro_rules! arg3 { () => ("%rcx") }
+#[cfg(windows)] macro_rules! arg0 { () => ("%rcx") }
+#[cfg(windows)] macro_rules! arg1 { () => ("%rdx") }
+#[cfg(windows)] macro_rules! arg2 { () => ("%r8") }
+#[cfg(windows)] macro_rules! arg3 { () => ("%r9") }
+
+global_asm!(concat!(
+    ".text\n",
+
+    // swap_registers(registers_t *oregs, registers_t *regs)
+    ".globl ", c_symbol!("rust_swap_registers"), "\n",
+    c_symbol!("rust_swap_registers"), ":\n",
+    "pop %rax\n",
+    "mov %rax, (8*8)(", arg0!(), ")\n",
+    save_registers!(arg0!()),
+    restore_registers!(arg1!()),
+    "mov (3*8)(", arg1!(), "), ", arg0!(), "\n",
+    "xor %eax, %eax\n",
+    "jmp *(8*8)(", arg1!(), ")\n",
+
+    // save_registers(registers_t *regs)
+    ".globl ", c_symbol!("rust_save_registers"), "\n",
+    c_symbol!("rust_save_registers"), ":\n",
+    "pop %rax\n",
+    "mov %rax, (8*8)(", arg0!(), ")\n",
+    save_registers!(arg0!()),
+    "jmp *(8*8)(", arg0!(), ")\n",
+
+    // load_registers(registers_t *regs)
+    ".globl ", c_symbol!("rust_load_registers"), "\n",
+    c_symbol!("rust_load_registers"), ":\n",
+    restore_registers!(arg0!()),
+    "mov ", arg0!(), ", %r11\n",
+    "mov (3*8)(", arg0!(), "), ", arg0!(), "\n",
+    "xor %eax, %eax\n",
+    "jmp *(8*8)(%r11)\n",
+
+    // jump_registers(registers_t *oregs, registers_t *regs, transfer_t *t) -> transfer_t *
+    ".globl ", c_symbol!("rust_jump_registers"), "\n",
+    c_symbol!("rust_jump_registers"), ":\n",
+    "pop %rax\n",
+    "mov %rax, (8*8)(", arg0!(), ")\n",
+    save_registers!(arg0!()),
+    restore_registers!(arg1!()),
+    "mov (3*8)(", arg1!(), "), ", arg0!(), "\n",
+    "mov ", arg2!(), ", %rax\n",
+    "jmp *(8*8)(", arg1!(), ")\n",
+
+    // ontop_registers(registers_t *oregs, registers_t *regs, transfer_t *t,
+    //                 transfer_t *(*fn)(transfer_t *)) -> transfer_t *
+    ".globl ", c_symbol!("rust_ontop_registers"), "\n",
+    c_symbol!("rust_ontop_registers"), ":\n",
+    "pop %rax\n",
+    "mov %rax, (8*8)(", arg0!(), ")\n",
+    save_registers!(arg0!()),
+    restore_registers!(arg1!()),
+    "push (8*8)(", arg1!(), ")\n",
+    "mov ", arg2!(), ", ", arg0!(), "\n",
+    "jmp *", arg3!(), "\n",
+
+    // The first code run by a new context
+    ".globl ", c_symbol!("rust_bootstrap_green_task"), "\n",
+    c_symbol!("rust_bootstrap_green_task"), ":\n",
+    "push $0\n",
+    "mov %r12, ", arg0!(), "\n",
+    "mov %r13, ", arg1!(), "\n",
+    "mov %rax, ", arg2!(), "\n",
+    "jmpq *%r14\n",
+), options(att_syntax));
@@ -37,15 +37,9 @@
     #define RUSTRT_XMM15 32
     #define RUSTRT_MAX   34
 #else
-    // Not used, just padding
-    #define RUSTRT_XXX   9
-    #define RUSTRT_XMM0 10
-    #define RUSTRT_XMM1 12
-    #define RUSTRT_XMM2 14
-    #define RUSTRT_XMM3 16
-    #define RUSTRT_XMM4 18
-    #define RUSTRT_XMM5 20
-    #define RUSTRT_MAX  22
+    // The MXCSR in the lower and the x87 control word in the upper half
+    #define RUSTRT_FPCW  9
+    #define RUSTRT_MAX   10
 #endif
 
 // ARG0 is the register in which the first argument goes.
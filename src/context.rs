@@ -11,12 +11,17 @@
 // FIXME: Silence the warning for `Registers`
 #![allow(improper_ctypes)]
 
-use stack::Stack;
-use std::usize;
+use overflow::{self, Recovery, StackOverflow};
+use stack::{Stack, StackSource, CANARY};
+use std::cell::{Cell, UnsafeCell};
+use std::fmt;
+use std::mem;
+use std::process;
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use libc;
-#[cfg(target_arch = "x86_64")]
-use simd;
 
 use sys;
 
@@ -24,20 +29,128 @@ use sys;
 pub struct Context {
     /// Hold the registers while the task or scheduler is suspended
     regs: Registers,
+    /// What is known about the stack the context runs on
+    stack: StackInfo,
+    /// The live part of a shared stack while the context is suspended
+    saved: SavedStack,
+}
+
+// The bounds of a stack, where its canary is, see `StackSource::canary`,
+// whether it is shared, and the fiber running on it. It is handed on to the
+// `out_context` of every switch, so it stays with the fiber whichever
+// context it got suspended in.
+#[derive(Debug, Copy, Clone)]
+struct StackInfo {
     /// Lower bound and upper bound for the stack
-    stack_bounds: Option<(usize, usize)>,
+    bounds: Option<(usize, usize)>,
+    canary: Option<(*const usize, usize)>,
+    shared: bool,
+    fiber: Option<FiberId>,
+}
+
+impl StackInfo {
+    const UNKNOWN: StackInfo = StackInfo { bounds: None, canary: None, shared: false, fiber: None };
+
+    // A new fiber on `stack`
+    fn of<S>(stack: &S, bounds: Option<(usize, usize)>) -> StackInfo
+        where S: StackSource + ?Sized
+    {
+        StackInfo {
+            bounds,
+            canary: stack.canary(),
+            shared: stack.is_shared(),
+            fiber: Some(FiberId::next()),
+        }
+    }
+}
+
+/// Tells fibers apart, see `current_context`
+///
+/// Every context made by `Context::new`, `Context::init_with`,
+/// `Context::with_closure` or `Context::from_raw_parts` starts a fiber with
+/// an identity of its own, which it keeps for as long as it runs, no matter
+/// which contexts it gets suspended in.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct FiberId(usize);
+
+impl FiberId {
+    fn next() -> FiberId {
+        static NEXT: AtomicUsize = AtomicUsize::new(1);
+
+        FiberId(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+// A copy of the stack from the saved stack pointer up to the top
+struct SavedStack(Vec<u8>);
+
+impl fmt::Debug for SavedStack {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SavedStack({} bytes)", self.0.len())
+    }
 }
 
 pub type InitFn = extern "C" fn(usize, *mut libc::c_void) -> !; // first argument is task handle, second is thunk ptr
 
+/// The value handed over by `Context::jump`.
+///
+/// `from` is the context which has been suspended to get here, so it can be
+/// resumed later on, and `data` is the payload passed to `Context::jump`.
+///
+/// `from` points at the `out_context` the other side passed to its switch,
+/// which usually lives on its stack. It is only valid until that context is
+/// resumed, by anyone, or dropped: after that it holds stale registers, or
+/// nothing at all. Resume it once, and don't keep it around past that.
+#[derive(Debug, Copy, Clone)]
+pub struct Transfer {
+    pub from: *mut Context,
+    pub data: usize,
+}
+
+impl Transfer {
+    pub fn new(from: *mut Context, data: usize) -> Transfer {
+        Transfer {
+            from,
+            data,
+        }
+    }
+}
+
+/// A function run by `Context::swap_ontop` on top of the resumed context
+pub type ResumeOntopFn = fn(Transfer) -> Transfer;
+
 impl Context {
     pub fn empty() -> Context {
         Context {
             regs: Registers::new(),
-            stack_bounds: None,
+            stack: StackInfo::UNKNOWN,
+            saved: SavedStack(Vec::new()),
         }
     }
 
+    /// The context of the code calling this, with the bounds of the stack it
+    /// runs on
+    ///
+    /// Unlike `Context::empty`, which leaves them unknown, this finds out
+    /// the real bounds of the stack of the thread with `pthread_getattr_np`,
+    /// or whatever the platform has got instead, while running on it. From
+    /// then on `current_stack_bounds` and `remaining_stack` work on the
+    /// thread as well. Use it like `Context::empty`, as the `out_context` of
+    /// the first switch to a fiber, which then knows where it got resumed
+    /// from.
+    pub fn current() -> Context {
+        let mut ctx = Context::empty();
+        ctx.stack = CURRENT_STACK.with(|current| {
+            let mut info = current.get();
+            if info.bounds.is_none() {
+                info.bounds = thread_stack_bounds();
+                current.set(info);
+            }
+            info
+        });
+        ctx
+    }
+
     /// Create a new context that will resume execution by running start
     ///
     /// The `init` function will be run with `arg` and the `start` procedure
@@ -47,19 +160,24 @@ impl Context {
     /// FIXME: this is basically an awful the interface. The main reason for
     ///        this is to reduce the number of allocations made when a green
     ///        task is spawned as much as possible
-    pub fn new(init: InitFn, arg: usize, start: *mut libc::c_void, stack: &mut Stack) -> Context {
+    pub fn new<S>(init: InitFn, arg: usize, start: *mut libc::c_void, stack: &mut S) -> Context
+        where S: StackSource + ?Sized
+    {
         let mut ctx = Context::empty();
         ctx.init_with(init, arg, start, stack);
         ctx
     }
 
-    pub fn init_with(&mut self, init: InitFn, arg: usize, start: *mut libc::c_void, stack: &mut Stack) {
+    pub fn init_with<S>(&mut self, init: InitFn, arg: usize, start: *mut libc::c_void, stack: &mut S)
+        where S: StackSource + ?Sized
+    {
         let sp: *const usize = stack.end();
         let sp: *mut usize = sp as *mut usize;
+        check_not_running_on(stack);
         // Save and then immediately load the current context,
         // which we will then modify to call the given function when restored
 
-        initialize_call_frame(&mut self.regs, init, arg, start, sp);
+        initialize_call_frame(&mut self.regs, init, arg, start, sp, stack.start() as usize);
 
         // Scheduler tasks don't have a stack in the "we allocated it" sense,
         // but rather they run on pthreads stacks. We have complete control over
@@ -67,53 +185,247 @@ impl Context {
         // overflow). Additionally, their coroutine stacks are listed as being
         // zero-length, so that's how we detect what's what here.
         let stack_base: *const usize = stack.start();
-        self.stack_bounds =
+        let bounds =
             if sp as libc::uintptr_t == stack_base as libc::uintptr_t {
                 None
             } else {
                 Some((stack_base as usize, sp as usize))
             };
+        self.stack = StackInfo::of(stack, bounds);
+        if self.stack.shared {
+            unsafe { save_stack(self) };
+        }
     }
 
-    /// Switch contexts
+    /// Create a new context that will run the closure `f` on `stack`
+    ///
+    /// The closure is moved onto the top of `stack`. It is called with the
+    /// `Transfer` that first switches into the new context, and the
+    /// `Transfer` it returns decides where to go once it has finished: the
+    /// context is left by jumping to `from` with `data`. The closure is
+    /// dropped before that last switch, so it doesn't leak as long as the
+    /// context is started at all.
+    ///
+    /// `f` must not panic, unwinding out of it aborts the process.
+    pub fn with_closure<S, F>(stack: &mut S, f: F) -> Context
+        where S: StackSource + ?Sized,
+              F: FnOnce(Transfer) -> Transfer + 'static
+    {
+        let mut ctx = Context::empty();
+        check_not_running_on(stack);
+
+        // Put the closure right below the top of the stack, the call frame
+        // will then be set up underneath it
+        let top = stack.end() as usize;
+        let fptr = (top - mem::size_of::<F>()) & !(mem::align_of::<F>() - 1);
+        unsafe { ptr::write(fptr as *mut F, f) };
 
+        // The bootstrap code passes the transfer as a third argument
+        let init: InitFn = unsafe {
+            mem::transmute(closure_init::<F> as extern "C" fn(usize, *mut libc::c_void, *const Transfer) -> !)
+        };
+        initialize_call_frame(&mut ctx.regs, init, 0, fptr as *mut libc::c_void, fptr as *mut usize,
+                              stack.start() as usize);
+        ctx.stack = StackInfo::of(stack, Some((stack.start() as usize, top)));
+        if ctx.stack.shared {
+            unsafe { save_stack(&mut ctx) };
+        }
+        ctx
+    }
+
+    /// Create a context which starts running the code at `ip` on a stack
+    /// ending at `sp`
+    ///
+    /// The code is entered as if it was an
+    /// `extern "C" fn(usize, usize, *const Transfer) -> !` called with the
+    /// two `args` and the `Transfer` of the first switch to the context,
+    /// like the init function of `Context::new`, but there is no `InitFn`
+    /// to go through. The bounds of the stack are unknown.
+    ///
+    /// # Safety
+    ///
+    /// `ip` has to point to code following the C calling convention of the
+    /// target, which must never return, and `sp` to the high end of memory
+    /// which it can use as its stack for as long as the context runs.
+    pub unsafe fn from_raw_parts(ip: usize, sp: usize, args: [usize; 2]) -> Context {
+        let mut ctx = Context::empty();
+        ctx.stack.fiber = Some(FiberId::next());
+        let init: InitFn = mem::transmute::<usize, InitFn>(ip);
+        initialize_call_frame(&mut ctx.regs, init, args[0], args[1] as *mut libc::c_void, sp as *mut usize, 0);
+        ctx
+    }
+
+    /// The fiber the context belongs to
+    ///
+    /// That is the one started by making the context, or for a context made
+    /// by `Context::empty` the one which got suspended in it. `None` for a
+    /// context which belongs to no fiber, such as the one of the thread
+    /// itself.
+    pub fn id(&self) -> Option<FiberId> {
+        self.stack.fiber
+    }
+
+    /// The stack pointer the context resumes with
+    ///
+    /// It is 0 for a context made by `Context::empty` which has not been
+    /// suspended yet. With the `ucontext` feature it is 0 as well where the
+    /// layout of the saved machine state isn't known, and so are
+    /// `instruction_pointer` and `frame_pointer`.
+    pub fn stack_pointer(&self) -> usize {
+        self.regs.stack_pointer()
+    }
+
+    /// The address of the code the context resumes at
+    ///
+    /// For a context which has not been started yet this is the crate's
+    /// trampoline calling the init function, not the init function itself.
+    pub fn instruction_pointer(&self) -> usize {
+        self.regs.instruction_pointer()
+    }
+
+    /// The frame pointer the context resumes with, only meaningful if the
+    /// code it got suspended in keeps one
+    pub fn frame_pointer(&self) -> usize {
+        self.regs.frame_pointer()
+    }
+
+    /// The lower and upper bound of the stack this context runs on
+    ///
+    /// Contexts made by `Context::new` or `Context::with_closure` span their
+    /// `Stack`, a suspended context the stack it got suspended on. `None`
+    /// means the stack is not one this crate knows the bounds of, like the
+    /// one of a thread.
+    pub fn stack_bounds(&self) -> Option<(usize, usize)> {
+        self.stack.bounds
+    }
+
+    /// Switch contexts
+    ///
     /// Suspend the current execution context and resume another by
     /// saving the registers values of the executing thread to a Context
     /// then loading the registers from a previously saved Context.
     pub fn swap(out_context: &mut Context, in_context: &Context) {
         debug!("swapping contexts");
-        let out_regs: &mut Registers = match out_context {
-            &mut Context { regs: ref mut r, .. } => r
-        };
-        let in_regs: &Registers = match in_context {
-            &Context { regs: ref r, .. } => r
-        };
+        check_suspendable(out_context);
 
-        debug!("noting the stack limit and doing raw swap");
+        debug!("noting the stack bounds and doing raw swap");
 
         unsafe {
-            // Right before we switch to the new context, set the new context's
-            // stack limit in the OS-specified TLS slot. This also  means that
-            // we cannot call any more rust functions after record_stack_bounds
-            // returns because they would all likely fail due to the limit being
-            // invalid for the current task. Lucky for us `rust_swap_registers`
-            // is a C function so we don't have to worry about that!
-            //
-            match in_context.stack_bounds {
-                Some((lo, hi)) => sys::stack::record_rust_managed_stack_bounds(lo, hi),
-                // If we're going back to one of the original contexts or
-                // something that's possibly not a "normal task", then reset
-                // the stack limit to 0 to make morestack never fail
-                None => sys::stack::record_rust_managed_stack_bounds(0, usize::MAX),
+            record_stack_bounds(&mut out_context.stack, in_context);
+            if out_context.stack.shared || in_context.stack.shared {
+                switch_copying(out_context, in_context, Resume::Swap, true);
+                return;
             }
+
+            let out_regs: &mut Registers = &mut out_context.regs;
+            let in_regs: &Registers = &in_context.regs;
             rust_swap_registers(out_regs, in_regs)
         }
     }
 
+    /// Switch contexts and pass a value along
+    ///
+    /// Works like `Context::swap`, but `data` is handed over to `in_context`
+    /// together with a pointer to `out_context`. The returned `Transfer` is
+    /// what was passed by whoever resumes `out_context` later on.
+    ///
+    /// A context started with `Context::new` receives the first `Transfer`
+    /// as a pointer in the third argument of its `InitFn`.
+    ///
+    /// If `out_context` is resumed by `Context::swap` or `Context::load`
+    /// instead, nothing is handed over and the returned `Transfer` has a null
+    /// `from` and a `data` of 0.
+    pub fn jump(out_context: &mut Context, in_context: &Context, data: usize) -> Transfer {
+        debug!("jumping between contexts");
+        check_suspendable(out_context);
+
+        unsafe {
+            record_stack_bounds(&mut out_context.stack, in_context);
+            if out_context.stack.shared || in_context.stack.shared {
+                return take_transfer(switch_copying(out_context, in_context, Resume::Jump(data), true));
+            }
+
+            let transfer = Transfer::new(out_context, data);
+            let out_regs: &mut Registers = &mut out_context.regs;
+            let in_regs: &Registers = &in_context.regs;
+
+            // The transfer lives on our own stack, which stays untouched
+            // while we are suspended, so the other side may read it until it
+            // switches away again.
+            take_transfer(rust_jump_registers(out_regs, in_regs, &transfer))
+        }
+    }
+
+    /// Switch contexts like `Context::jump`, but survive `in_context`
+    /// overflowing its stack
+    ///
+    /// If `in_context` runs into the guard page of its `Stack` before the
+    /// next switch back to `out_context`, the fault handler makes the thread
+    /// go on in a landing pad, which switches back here with a
+    /// `StackOverflow`. Nothing is unwound, and `in_context` must not be
+    /// resumed again, but its stack may be dropped and the rest of the process
+    /// keeps running.
+    ///
+    /// This takes the handler of `overflow::install_handler`, installed
+    /// before the stack got allocated, and is available on Linux on x86,
+    /// x86_64 and aarch64. Elsewhere the overflow
+    /// is reported and the process aborted as usual.
+    pub fn try_jump(out_context: &mut Context, in_context: &Context, data: usize)
+                    -> Result<Transfer, StackOverflow> {
+        let recovery = in_context.stack.bounds.map(|(_, hi)| Recovery {
+            to: out_context,
+            stack_hi: hi,
+        });
+        let outer = overflow::set_recovery(recovery);
+        let t = Context::jump(out_context, in_context, data);
+        overflow::set_recovery(outer);
+
+        match overflow::take_overflow() {
+            Some(e) => Err(e),
+            None => Ok(t),
+        }
+    }
+
+    /// Switch contexts and run `f` on top of `in_context` before it resumes
+    ///
+    /// Works like `Context::jump`, but `f` is called on the stack of
+    /// `in_context` with the `Transfer` carrying `data`, and whatever it
+    /// returns is what `in_context` gets handed over instead. This is the
+    /// place to free the stack of the context we have just left, or to
+    /// inject a panic into a suspended context: it unwinds the stack of
+    /// `in_context` from the point where it got suspended by `Context::jump`
+    /// or `Context::swap`. A panic on top of a context which has not been
+    /// started yet aborts the process, and so does any panic on top on x86
+    /// and powerpc64, where `f` is called from an assembly stub.
+    pub fn swap_ontop(out_context: &mut Context, in_context: &Context, data: usize,
+                      f: ResumeOntopFn) -> Transfer {
+        debug!("jumping between contexts with a function on top");
+        check_suspendable(out_context);
+
+        unsafe {
+            record_stack_bounds(&mut out_context.stack, in_context);
+            if out_context.stack.shared || in_context.stack.shared {
+                return take_transfer(switch_copying(out_context, in_context, Resume::Ontop(data, f), true));
+            }
+
+            let ontop = Ontop {
+                transfer: Transfer::new(out_context, data),
+                f,
+            };
+            let out_regs: &mut Registers = &mut out_context.regs;
+            let in_regs: &Registers = &in_context.regs;
+            take_transfer(rust_ontop_registers(out_regs, in_regs,
+                                               &ontop as *const Ontop as *const Transfer,
+                                               ontop_trampoline))
+        }
+    }
+
     /// Save the current context.
     #[inline(always)]
     pub fn save(context: &mut Context) {
         let regs: &mut Registers = &mut context.regs;
+        context.stack = CURRENT_STACK.with(|s| s.get());
+        assert!(!context.stack.shared, "Context::save can't save a shared stack");
 
         unsafe {
             rust_save_registers(regs);
@@ -127,30 +439,337 @@ impl Context {
         let regs: &Registers = &to_context.regs;
 
         unsafe {
-            // Right before we switch to the new context, set the new context's
-            // stack limit in the OS-specified TLS slot. This also  means that
-            // we cannot call any more rust functions after record_stack_bounds
-            // returns because they would all likely fail due to the limit being
-            // invalid for the current task. Lucky for us `rust_swap_registers`
-            // is a C function so we don't have to worry about that!
-            //
-            match to_context.stack_bounds {
-                Some((lo, hi)) => sys::stack::record_rust_managed_stack_bounds(lo, hi),
-                // If we're going back to one of the original contexts or
-                // something that's possibly not a "normal task", then reset
-                // the stack limit to 0 to make morestack never fail
-                None => sys::stack::record_rust_managed_stack_bounds(0, usize::MAX),
+            // Nothing is suspended on the stack we are leaving
+            let mut left = Context::empty();
+            record_stack_bounds(&mut left.stack, to_context);
+            if left.stack.shared || to_context.stack.shared {
+                switch_copying(&mut left, to_context, Resume::Swap, false);
+                unreachable!("Should never comeback");
             }
-
             rust_load_registers(regs);
         }
     }
 }
 
-extern {
+// The crate-owned init function of contexts made by `Context::with_closure`
+extern "C" fn closure_init<F>(_: usize, f: *mut libc::c_void, t: *const Transfer) -> !
+    where F: FnOnce(Transfer) -> Transfer
+{
+    let t = {
+        // Move the closure off the stack top, it will be dropped once called
+        let func: F = unsafe { ptr::read(f as *mut F) };
+        func(unsafe { take_transfer(t) })
+    };
+
+    assert!(!t.from.is_null(), "Context::with_closure returned a Transfer without a context to switch to");
+
+    // Nothing is left to be saved, this context will never be resumed
+    let mut finished = Context::empty();
+    let to = unsafe { &*t.from };
+    unsafe {
+        record_stack_bounds(&mut finished.stack, to);
+        if finished.stack.shared || to.stack.shared {
+            switch_copying(&mut finished, to, Resume::Jump(t.data), false);
+        } else {
+            rust_jump_registers(&mut finished.regs, &to.regs, &Transfer::new(&mut finished, t.data));
+        }
+    }
+
+    unreachable!("Should never comeback");
+}
+
+// What `Context::swap_ontop` hands over to `ontop_trampoline`
+#[repr(C)]
+struct Ontop {
+    transfer: Transfer,
+    f: ResumeOntopFn,
+}
+
+thread_local!(static ONTOP_RESULT: Cell<Transfer> = Cell::new(Transfer::new(ptr::null_mut(), 0)));
+
+// Called by rust_ontop_registers on top of the resumed context. Everything is
+// copied out of `t` first, as `f` may well free the stack it lives on. For the
+// same reason the result is kept in a thread local, it only has to stay
+// around until the resumed side has taken it.
+extern "C-unwind" fn ontop_trampoline(t: *const Transfer) -> *const Transfer {
+    let ontop = unsafe { ptr::read(t as *const Ontop) };
+    let t = (ontop.f)(ontop.transfer);
+    ONTOP_RESULT.with(|r| {
+        r.set(t);
+        r.as_ptr() as *const Transfer
+    })
+}
+
+// Copy the transfer handed over by the assembly, null if there is none
+#[inline(always)]
+unsafe fn take_transfer(t: *const Transfer) -> Transfer {
+    if t.is_null() {
+        Transfer::new(ptr::null_mut(), 0)
+    } else {
+        *t
+    }
+}
+
+thread_local!(static CURRENT_STACK: Cell<StackInfo> = const { Cell::new(StackInfo::UNKNOWN) });
+
+thread_local! {
+    static THREAD_STACK: Cell<Option<Option<(usize, usize)>>> = const { Cell::new(None) };
+}
+
+// The bounds of the stack of this thread itself, looked up once
+fn thread_stack_bounds() -> Option<(usize, usize)> {
+    THREAD_STACK.with(|bounds| {
+        if bounds.get().is_none() {
+            bounds.set(Some(sys::thread::stack_bounds()));
+        }
+        bounds.get().unwrap()
+    })
+}
+
+/// The fiber running on this thread
+///
+/// Returns the identity of the fiber the context most recently switched to
+/// on this thread belongs to, the same as `Context::id` of the context it
+/// was made with, or `None` if the thread runs on its own: before the first
+/// switch, or after switching back to a context made by `Context::empty` or
+/// `Context::current` on the thread.
+pub fn current_context() -> Option<FiberId> {
+    CURRENT_STACK.with(|s| s.get().fiber)
+}
+
+/// The stack bounds of the context running on this thread
+///
+/// Returns the lower and upper bound of the stack, like
+/// `Context::stack_bounds`, of the context most recently switched to on
+/// the current thread, or `None` while the thread runs on a stack this crate
+/// doesn't know about, e.g. its own before `Context::current` was called.
+pub fn current_stack_bounds() -> Option<(usize, usize)> {
+    CURRENT_STACK.with(|s| s.get().bounds)
+}
+
+/// The number of bytes left on the stack of the running context
+///
+/// Returns `None` if the bounds of the current stack aren't known, see
+/// `current_stack_bounds`.
+#[inline(never)]
+pub fn remaining_stack() -> Option<usize> {
+    let marker = 0u8;
+    let sp = &marker as *const u8 as usize;
+    current_stack_bounds().map(|(lo, _)| sp.saturating_sub(lo))
+}
+
+// Right before we switch to the new context, make its stack bounds the
+// current ones. The context being suspended keeps running on the stack we are
+// leaving, so it is the one to remember the bounds we had so far. That stack
+// has to be intact, or we'd rather not go on at all.
+#[inline(always)]
+unsafe fn record_stack_bounds(out_stack: &mut StackInfo, to_context: &Context) {
+    *out_stack = CURRENT_STACK.with(|s| s.replace(to_context.stack));
+    check_canary(out_stack);
+
+    match to_context.stack.bounds {
+        Some((lo, hi)) => sys::stack::record_os_stack_bounds(lo, hi),
+        // If we're going back to one of the original contexts or
+        // something that's possibly not a "normal task", then
+        // let the stack span everything
+        None => sys::stack::record_os_stack_bounds(0, usize::MAX),
+    }
+}
+
+// Abort if the canary of `stack` got overwritten
+#[inline(always)]
+unsafe fn check_canary(stack: &StackInfo) {
+    if let Some((canary, words)) = stack.canary {
+        if (0..words).any(|idx| ptr::read_volatile(canary.add(idx)) != CANARY) {
+            canary_corrupted(stack);
+        }
+    }
+}
+
+#[cold]
+#[inline(never)]
+fn canary_corrupted(stack: &StackInfo) -> ! {
+    use std::io::{self, Write};
+
+    let (lo, hi) = stack.bounds.unwrap_or((0, 0));
+    let _ = match overflow::name_of(hi) {
+        Some(name) => writeln!(io::stderr(), "fiber stack overflow: the canary of the stack '{}' at {:#x}-{:#x} \
+                                              has been overwritten, aborting", name, lo, hi),
+        None => writeln!(io::stderr(), "fiber stack overflow: the canary of the stack at {:#x}-{:#x} \
+                                        has been overwritten, aborting", lo, hi),
+    };
+    process::abort();
+}
+
+// Where the fault handler makes a thread go on once a context resumed by
+// `Context::try_jump` has overflowed its stack, on an emergency area. That
+// context is gone, only the one waiting in `Context::try_jump` is left to
+// switch to.
+pub(crate) extern "C" fn overflow_landing_pad() -> ! {
+    let to = overflow::land();
+
+    // Neither the canary nor the live part of the stack we are leaving are
+    // worth looking at anymore
+    CURRENT_STACK.with(|s| s.set(StackInfo::UNKNOWN));
+    let mut gone = Context::empty();
+    Context::jump(&mut gone, unsafe { &*to }, 0);
+
+    unreachable!("Should never comeback");
+}
+
+// Contexts on a shared stack are switched from and to by way of the copier,
+// a context running on a stack of its own. It saves the live part of the
+// stack of the context which has just been suspended, puts the one of the
+// context to resume in place, and then resumes it the way it was asked to.
+// Neither can be done by either side while it runs on the shared stack.
+struct Copier {
+    ctx: Context,
+    _stack: Stack,
+}
+
+// How the copier is to resume a context
+#[derive(Copy, Clone)]
+enum Resume {
+    Swap,
+    Jump(usize),
+    Ontop(usize, ResumeOntopFn),
+}
+
+// What the copier gets handed over by `switch_copying`
+struct CopyRequest {
+    to: *const Context,
+    resume: Resume,
+    // Whether the suspended context may ever be resumed
+    save: bool,
+}
+
+const COPIER_STACK_SIZE: usize = 64 * 1024;
+
+thread_local!(static COPIER: UnsafeCell<Option<Box<Copier>>> = const { UnsafeCell::new(None) });
+
+// The copier of this thread, made on first use
+fn copier() -> *mut Context {
+    COPIER.with(|copier| {
+        let copier = unsafe { &mut *copier.get() };
+        let copier = copier.get_or_insert_with(|| {
+            let mut stack = Stack::new(COPIER_STACK_SIZE);
+            Box::new(Copier {
+                ctx: Context::with_closure(&mut stack, run_copier),
+                _stack: stack,
+            })
+        });
+        &mut copier.ctx as *mut Context
+    })
+}
+
+fn run_copier(mut t: Transfer) -> Transfer {
+    loop {
+        // Read the request before the stack it lives on gets overwritten
+        let request = unsafe { ptr::read(t.data as *const CopyRequest) };
+        let from = t.from;
+        let to = unsafe { &*request.to };
+        let regs = unsafe { &mut (*copier()).regs };
+
+        unsafe {
+            if request.save && (*from).stack.shared {
+                save_stack(&mut *from);
+            }
+            if to.stack.shared {
+                restore_stack(to);
+            }
+
+            t = take_transfer(match request.resume {
+                Resume::Swap => rust_jump_registers(regs, &to.regs, ptr::null()),
+                Resume::Jump(data) => {
+                    let transfer = Transfer::new(from, data);
+                    rust_jump_registers(regs, &to.regs, &transfer)
+                }
+                Resume::Ontop(data, f) => {
+                    let ontop = Ontop {
+                        transfer: Transfer::new(from, data),
+                        f,
+                    };
+                    rust_ontop_registers(regs, &to.regs, &ontop as *const Ontop as *const Transfer,
+                                         ontop_trampoline)
+                }
+            });
+        }
+    }
+}
+
+// Suspend `out_context` and have the copier resume `in_context`, the stack
+// bounds have been recorded already
+#[inline(never)]
+unsafe fn switch_copying(out_context: &mut Context, in_context: &Context, resume: Resume,
+                         save: bool) -> *const Transfer {
+    let request = CopyRequest {
+        to: in_context,
+        resume,
+        save,
+    };
+    let copier = &*copier();
+    let transfer = Transfer::new(out_context, &request as *const CopyRequest as usize);
+    rust_jump_registers(&mut out_context.regs, &copier.regs, &transfer)
+}
+
+// Copy the live part of the shared stack of the suspended `ctx` to the heap
+unsafe fn save_stack(ctx: &mut Context) {
+    if let Some((lo, hi)) = ctx.stack.bounds {
+        // All of it, if the saved stack pointer isn't known, see the docs of
+        // `SharedStack`
+        let sp = ctx.regs.stack_pointer();
+        let sp = if sp < lo || sp > hi { lo } else { sp };
+
+        let saved = &mut ctx.saved.0;
+        saved.clear();
+        saved.extend_from_slice(slice::from_raw_parts(sp as *const u8, hi - sp));
+    }
+}
+
+// Put what `save_stack` copied back onto the shared stack of `ctx`
+unsafe fn restore_stack(ctx: &Context) {
+    if let Some((_, hi)) = ctx.stack.bounds {
+        let saved = &ctx.saved.0;
+        ptr::copy_nonoverlapping(saved.as_ptr(), (hi - saved.len()) as *mut u8, saved.len());
+    }
+}
+
+// The copier can only resume a context which doesn't live on the shared
+// stack it gets suspended on, as that is overwritten in the meantime
+#[inline(always)]
+fn check_suspendable(out_context: &Context) {
+    let current = CURRENT_STACK.with(|s| s.get());
+    if let (true, Some((lo, hi))) = (current.shared, current.bounds) {
+        let addr = out_context as *const Context as usize;
+        assert!(addr < lo || addr >= hi, "a context suspended on a shared stack must not live on it");
+    }
+}
+
+// Setting up a context on a shared stack overwrites whatever is on it
+fn check_not_running_on<S>(stack: &S)
+    where S: StackSource + ?Sized
+{
+    if stack.is_shared() {
+        let marker = 0u8;
+        let sp = &marker as *const u8 as usize;
+        assert!(sp < stack.start() as usize || sp >= stack.end() as usize,
+                "can't make a context on the shared stack it runs on");
+    }
+}
+
+// A panic raised on top of a context by `Context::swap_ontop` unwinds out of
+// the switch which suspended that context
+extern "C-unwind" {
     fn rust_swap_registers(out_regs: *mut Registers, in_regs: *const Registers);
+    // Nothing to wrap with ucontext, see ucontext/_context.c
+    #[cfg_attr(feature = "ucontext", link_name = "getcontext")]
     fn rust_save_registers(out_regs: *mut Registers);
     fn rust_load_registers(in_regs: *const Registers) -> !;
+    fn rust_jump_registers(out_regs: *mut Registers, in_regs: *const Registers,
+                           transfer: *const Transfer) -> *const Transfer;
+    fn rust_ontop_registers(out_regs: *mut Registers, in_regs: *const Registers,
+                            transfer: *const Transfer,
+                            f: extern "C-unwind" fn(*const Transfer) -> *const Transfer) -> *const Transfer;
 }
 
 // Register contexts used in various architectures
@@ -176,7 +795,7 @@ extern {
 // the `rust_swap_registers` function, but that's only because for now segmented
 // stacks are disabled.
 
-#[cfg(target_arch = "x86")]
+#[cfg(all(not(feature = "ucontext"), target_arch = "x86"))]
 #[repr(C)]
 #[derive(Debug)]
 struct Registers {
@@ -186,7 +805,7 @@ struct Registers {
     eflags: u32, eip: u32
 }
 
-#[cfg(target_arch = "x86")]
+#[cfg(all(not(feature = "ucontext"), target_arch = "x86"))]
 impl Registers {
     fn new() -> Registers {
         Registers {
@@ -196,10 +815,24 @@ impl Registers {
             eflags: 0, eip: 0,
         }
     }
+
+    fn stack_pointer(&self) -> usize {
+        self.esp as usize
+    }
+
+    fn instruction_pointer(&self) -> usize {
+        self.eip as usize
+    }
+
+    fn frame_pointer(&self) -> usize {
+        self.ebp as usize
+    }
 }
 
-#[cfg(target_arch = "x86")]
-fn initialize_call_frame(regs: &mut Registers, fptr: InitFn, arg: usize, thunkptr: *mut libc::c_void, sp: *mut usize) {
+#[cfg(all(not(feature = "ucontext"), target_arch = "x86"))]
+fn initialize_call_frame(regs: &mut Registers, fptr: InitFn, arg: usize, thunkptr: *mut libc::c_void, sp: *mut usize,
+                         _stack_lo: usize) {
+    extern "C" { fn rust_bootstrap_green_task(); } // puts the transfer into the third argument
     // x86 has interesting stack alignment requirements, so do some alignment
     // plus some offsetting to figure out what the actual stack should be.
     let sp = align_down(sp);
@@ -208,7 +841,7 @@ fn initialize_call_frame(regs: &mut Registers, fptr: InitFn, arg: usize, thunkpt
     |----------------+----------------------+---------------+-------|
     | position(high) | data                 | comment       |       |
     |----------------+----------------------+---------------+-------|
-    |             +3 | null                 |               |       |
+    |             +3 | transfer             | by bootstrap  |       |
     |             +2 | boxed_thunk_ptr      |               |       |
     |             +1 | argptr               | taskhandleptr |       |
     |              0 | retaddr(0) no return |               | <- sp |
@@ -219,7 +852,8 @@ fn initialize_call_frame(regs: &mut Registers, fptr: InitFn, arg: usize, thunkpt
     unsafe { *mut_offset(sp, 0) = 0 }; // The final return address, 0 because of !
 
     regs.esp = sp as u32;
-    regs.eip = fptr as u32;
+    regs.esi = fptr as usize as u32;
+    regs.eip = rust_bootstrap_green_task as *const () as u32;
 
     // Last base pointer on the stack is 0
     regs.ebp = 0;
@@ -227,45 +861,70 @@ fn initialize_call_frame(regs: &mut Registers, fptr: InitFn, arg: usize, thunkpt
 
 // windows requires saving more registers (both general and XMM), so the windows
 // register context must be larger.
-#[cfg(all(windows, target_arch = "x86_64"))]
-#[repr(C)]
+#[cfg(all(not(feature = "ucontext"), windows, target_arch = "x86_64"))]
+#[repr(C, align(16))]
 #[derive(Debug)]
 struct Registers {
     gpr: [libc::uintptr_t; 14],
-    _xmm: [simd::u32x4; 10]
+    _xmm: [u8; 10 * 16]
 }
 
-#[cfg(all(windows, target_arch = "x86_64"))]
+#[cfg(all(not(feature = "ucontext"), windows, target_arch = "x86_64"))]
 impl Registers {
     fn new() -> Registers {
         Registers {
             gpr: [0; 14],
-            _xmm: [simd::u32x4::new(0,0,0,0); 10]
+            _xmm: [0; 10 * 16]
         }
     }
+
+    fn stack_pointer(&self) -> usize {
+        self.gpr[1] as usize // RUSTRT_RSP
+    }
+
+    fn instruction_pointer(&self) -> usize {
+        self.gpr[8] as usize // RUSTRT_IP
+    }
+
+    fn frame_pointer(&self) -> usize {
+        self.gpr[2] as usize // RUSTRT_RBP
+    }
 }
 
-#[cfg(all(not(windows), target_arch = "x86_64"))]
+// All the XMM registers are caller-saved in the System V ABI, only the MXCSR and
+// the x87 control word have to be kept
+#[cfg(all(not(feature = "ucontext"), not(windows), target_arch = "x86_64"))]
 #[repr(C)]
 #[derive(Debug)]
 struct Registers {
     gpr: [libc::uintptr_t; 10],
-    _xmm: [simd::u32x4; 6]
 }
 
-#[cfg(all(not(windows), target_arch = "x86_64"))]
+#[cfg(all(not(feature = "ucontext"), not(windows), target_arch = "x86_64"))]
 impl Registers {
     fn new() -> Registers {
         Registers {
             gpr: [0; 10],
-            _xmm: [simd::u32x4::new(0,0,0,0); 6]
         }
     }
+
+    fn stack_pointer(&self) -> usize {
+        self.gpr[1] as usize // RUSTRT_RSP
+    }
+
+    fn instruction_pointer(&self) -> usize {
+        self.gpr[8] as usize // RUSTRT_IP
+    }
+
+    fn frame_pointer(&self) -> usize {
+        self.gpr[2] as usize // RUSTRT_RBP
+    }
 }
 
-#[cfg(target_arch = "x86_64")]
-fn initialize_call_frame(regs: &mut Registers, fptr: InitFn, arg: usize, thunkptr: *mut libc::c_void, sp: *mut usize) {
-    extern { fn rust_bootstrap_green_task(); } // use an indirection because the call contract differences between windows and linux
+#[cfg(all(not(feature = "ucontext"), target_arch = "x86_64"))]
+fn initialize_call_frame(regs: &mut Registers, fptr: InitFn, arg: usize, thunkptr: *mut libc::c_void, sp: *mut usize,
+                         _stack_lo: usize) {
+    extern "C" { fn rust_bootstrap_green_task(); } // use an indirection because the call contract differences between windows and linux
     // TODO: use rust's condition compile attribute instead
 
     // Redefinitions from rt/arch/x86_64/regs.h
@@ -276,15 +935,18 @@ fn initialize_call_frame(regs: &mut Registers, fptr: InitFn, arg: usize, thunkpt
     static RUSTRT_R13: usize = 5;
     static RUSTRT_R14: usize = 6;
     // static RUSTRT_R15: usize = 7;
+    #[cfg(not(windows))]
+    static RUSTRT_FPCW: usize = 9;
 
+    // Keep sp 16-byte aligned, the final return address is pushed by
+    // rust_bootstrap_green_task, so that a function run on top of a fresh
+    // context by `Context::swap_ontop` is entered with a properly aligned
+    // stack as well. The 32 bytes left above are the shadow space of Win64.
     let sp = align_down(sp);
-    let sp = mut_offset(sp, -1);
-
-    // The final return address. 0 indicates the bottom of the stack
-    unsafe { *sp = 0; }
+    let sp = mut_offset(sp, -4);
 
     debug!("creating call framenn");
-    debug!("fptr {:#x}", fptr as libc::uintptr_t);
+    debug!("fptr {:#x}", fptr as usize);
     debug!("arg {:#x}", arg);
     debug!("sp {:?}", sp);
 
@@ -292,34 +954,55 @@ fn initialize_call_frame(regs: &mut Registers, fptr: InitFn, arg: usize, thunkpt
     // location so we can invoke the "real init function", `fptr`.
     regs.gpr[RUSTRT_R12] = arg as libc::uintptr_t;
     regs.gpr[RUSTRT_R13] = thunkptr as libc::uintptr_t;
-    regs.gpr[RUSTRT_R14] = fptr as libc::uintptr_t;
+    regs.gpr[RUSTRT_R14] = fptr as usize as libc::uintptr_t;
 
     // These registers are picked up by the regular context switch paths. These
     // will put us in "mostly the right context" except for frobbing all the
     // arguments to the right place. We have the small trampoline code inside of
     // rust_bootstrap_green_task to do that.
     regs.gpr[RUSTRT_RSP] = sp as libc::uintptr_t;
-    regs.gpr[RUSTRT_IP] = rust_bootstrap_green_task as libc::uintptr_t;
+    regs.gpr[RUSTRT_IP] = rust_bootstrap_green_task as *const () as libc::uintptr_t;
 
     // Last base pointer on the stack should be 0
     regs.gpr[RUSTRT_RBP] = 0;
+
+    // A new context starts with the default floating point environment: all
+    // exceptions masked, round to nearest, and extended precision for x87
+    #[cfg(not(windows))]
+    {
+        regs.gpr[RUSTRT_FPCW] = 0x037F << 32 | 0x1F80;
+    }
 }
 
-#[cfg(target_arch = "arm")]
+#[cfg(all(not(feature = "ucontext"), target_arch = "arm"))]
 #[repr(C)]
 #[derive(Debug)]
 struct Registers([libc::uintptr_t; 32]);
 
-#[cfg(target_arch = "arm")]
+#[cfg(all(not(feature = "ucontext"), target_arch = "arm"))]
 impl Registers {
     fn new() -> Registers {
         Registers([0; 32])
     }
+
+    fn stack_pointer(&self) -> usize {
+        self.0[13] as usize // r13
+    }
+
+    fn instruction_pointer(&self) -> usize {
+        self.0[14] as usize // lr, which we return to
+    }
+
+    fn frame_pointer(&self) -> usize {
+        // r7 in Thumb code, r11 otherwise
+        if cfg!(target_feature = "thumb-mode") { self.0[7] as usize } else { self.0[11] as usize }
+    }
 }
 
-#[cfg(target_arch = "arm")]
-fn initialize_call_frame(regs: &mut Registers, fptr: InitFn, arg: usize, thunkptr: *mut libc::c_void, sp: *mut usize) {
-    extern { fn rust_bootstrap_green_task(); } // same as the x64 arch
+#[cfg(all(not(feature = "ucontext"), target_arch = "arm"))]
+fn initialize_call_frame(regs: &mut Registers, fptr: InitFn, arg: usize, thunkptr: *mut libc::c_void, sp: *mut usize,
+                         _stack_lo: usize) {
+    extern "C" { fn rust_bootstrap_green_task(); } // same as the x64 arch
 
     let sp = align_down(sp);
     // sp of arm eabi is 8-byte aligned
@@ -331,32 +1014,189 @@ fn initialize_call_frame(regs: &mut Registers, fptr: InitFn, arg: usize, thunkpt
     let &mut Registers(ref mut regs) = regs;
 
     // ARM uses the same technique as x86_64 to have a landing pad for the start
-    // of all new green tasks. Neither r1/r2 are saved on a context switch, so
-    // the shim will copy r3/r4 into r1/r2 and then execute the function in r5
-    regs[0] = arg as libc::uintptr_t;              // r0
-    regs[3] = thunkptr as libc::uintptr_t;         // r3
-    regs[5] = fptr as libc::uintptr_t;             // r5
+    // of all new green tasks. Neither r1/r2 are saved on a context switch and
+    // r0 carries the transfer, so the shim will move r0 into r2, copy r4/r6
+    // into r0/r1 and then execute the function in r5
+    regs[4] = arg as libc::uintptr_t;              // r4
+    regs[6] = thunkptr as libc::uintptr_t;         // r6
+    regs[5] = fptr as usize as libc::uintptr_t;    // r5
     regs[13] = sp as libc::uintptr_t;                          // #52 sp, r13
-    regs[14] = rust_bootstrap_green_task as libc::uintptr_t;   // #56 pc, r14 --> lr
+    regs[14] = rust_bootstrap_green_task as *const () as libc::uintptr_t;   // #56 pc, r14 --> lr
+}
+
+// x19-x30, sp and d8-d15, see the layout in aarch64/_context.S
+#[cfg(all(not(feature = "ucontext"), target_arch = "aarch64"))]
+#[repr(C)]
+#[derive(Debug)]
+struct Registers([libc::uintptr_t; 21]);
+
+#[cfg(all(not(feature = "ucontext"), target_arch = "aarch64"))]
+impl Registers {
+    fn new() -> Registers {
+        Registers([0; 21])
+    }
+
+    fn stack_pointer(&self) -> usize {
+        self.0[12] as usize // sp
+    }
+
+    fn instruction_pointer(&self) -> usize {
+        self.0[11] as usize // x30, lr
+    }
+
+    fn frame_pointer(&self) -> usize {
+        self.0[10] as usize // x29
+    }
+}
+
+#[cfg(all(not(feature = "ucontext"), target_arch = "aarch64"))]
+fn initialize_call_frame(regs: &mut Registers, fptr: InitFn, arg: usize, thunkptr: *mut libc::c_void, sp: *mut usize,
+                         _stack_lo: usize) {
+    extern "C" { fn rust_bootstrap_green_task(); } // same as the x64 arch
+
+    // sp of aarch64 is 16-byte aligned, there is no return address on the
+    // stack, it lives in x30
+    let sp = align_down(sp);
+
+    let &mut Registers(ref mut regs) = regs;
+
+    // The shim will copy x19/x20 into x0/x1, pass the transfer in x2 and
+    // then execute the function in x21
+    regs[0] = arg as libc::uintptr_t;                          // x19
+    regs[1] = thunkptr as libc::uintptr_t;                     // x20
+    regs[2] = fptr as usize as libc::uintptr_t;                // x21
+    regs[10] = 0;                                              // x29, last frame pointer
+    regs[11] = rust_bootstrap_green_task as *const () as libc::uintptr_t;   // x30, lr
+    regs[12] = sp as libc::uintptr_t;                          // sp
+}
+
+// ra, sp, s0-s11 and fs0-fs11, see the layout in riscv64/_context.S
+#[cfg(all(not(feature = "ucontext"), target_arch = "riscv64"))]
+#[repr(C)]
+#[derive(Debug)]
+struct Registers([libc::uintptr_t; 26]);
+
+#[cfg(all(not(feature = "ucontext"), target_arch = "riscv64"))]
+impl Registers {
+    fn new() -> Registers {
+        Registers([0; 26])
+    }
+
+    fn stack_pointer(&self) -> usize {
+        self.0[1] as usize // sp
+    }
+
+    fn instruction_pointer(&self) -> usize {
+        self.0[0] as usize // ra
+    }
+
+    fn frame_pointer(&self) -> usize {
+        self.0[2] as usize // s0
+    }
 }
 
-#[cfg(any(target_arch = "mips",
-          target_arch = "mipsel"))]
+#[cfg(all(not(feature = "ucontext"), target_arch = "riscv64"))]
+fn initialize_call_frame(regs: &mut Registers, fptr: InitFn, arg: usize, thunkptr: *mut libc::c_void, sp: *mut usize,
+                         _stack_lo: usize) {
+    extern "C" { fn rust_bootstrap_green_task(); } // same as the x64 arch
+
+    // The psABI requires sp to be 16-byte aligned, the return address
+    // lives in ra
+    let sp = align_down(sp);
+
+    let &mut Registers(ref mut regs) = regs;
+
+    // The shim will copy s1/s2 into a0/a1, pass the transfer in a2 and
+    // then execute the function in s3
+    regs[0] = rust_bootstrap_green_task as *const () as libc::uintptr_t;   // ra
+    regs[1] = sp as libc::uintptr_t;                          // sp
+    regs[2] = 0;                                              // s0, last frame pointer
+    regs[3] = arg as libc::uintptr_t;                         // s1
+    regs[4] = thunkptr as libc::uintptr_t;                    // s2
+    regs[5] = fptr as usize as libc::uintptr_t;               // s3
+}
+
+// r1, r2, LR, CR, r14-r31, f14-f31 and v20-v31, see the layout in
+// powerpc64le/_context.S
+#[cfg(all(not(feature = "ucontext"), target_arch = "powerpc64", target_endian = "little"))]
+#[repr(C)]
+#[derive(Debug)]
+struct Registers([libc::uintptr_t; 64]);
+
+#[cfg(all(not(feature = "ucontext"), target_arch = "powerpc64", target_endian = "little"))]
+impl Registers {
+    fn new() -> Registers {
+        Registers([0; 64])
+    }
+
+    fn stack_pointer(&self) -> usize {
+        self.0[0] as usize // r1
+    }
+
+    fn instruction_pointer(&self) -> usize {
+        self.0[2] as usize // LR
+    }
+
+    fn frame_pointer(&self) -> usize {
+        self.0[21] as usize // r31
+    }
+}
+
+#[cfg(all(not(feature = "ucontext"), target_arch = "powerpc64", target_endian = "little"))]
+fn initialize_call_frame(regs: &mut Registers, fptr: InitFn, arg: usize, thunkptr: *mut libc::c_void, sp: *mut usize,
+                         _stack_lo: usize) {
+    extern "C" { fn rust_bootstrap_green_task(); } // same as the x64 arch
+
+    // sp of ELFv2 is 16-byte aligned and points to the back chain of a
+    // minimal 32-byte frame header, which the init function may store its
+    // return address and our TOC into
+    let sp = align_down(sp);
+    let sp = mut_offset(sp, -4);
+
+    // The final back chain. 0 indicates the bottom of the stack
+    unsafe { *sp = 0; }
+
+    let &mut Registers(ref mut regs) = regs;
+
+    // The shim will copy r14/r15 into r3/r4, pass the transfer in r5 and
+    // then execute the function in r16 through r12, which its global entry
+    // point sets up the TOC from
+    regs[0] = sp as libc::uintptr_t;                          // r1
+    regs[2] = rust_bootstrap_green_task as *const () as libc::uintptr_t;   // LR
+    regs[4] = arg as libc::uintptr_t;                         // r14
+    regs[5] = thunkptr as libc::uintptr_t;                    // r15
+    regs[6] = fptr as usize as libc::uintptr_t;               // r16
+}
+
+#[cfg(all(not(feature = "ucontext"), target_arch = "mips"))]
 #[repr(C)]
 #[derive(Debug)]
 struct Registers([libc::uintptr_t; 32]);
 
-#[cfg(any(target_arch = "mips",
-          target_arch = "mipsel"))]
+#[cfg(all(not(feature = "ucontext"), target_arch = "mips"))]
 impl Registers {
     fn new() -> Registers {
         Registers([0; 32])
     }
+
+    fn stack_pointer(&self) -> usize {
+        self.0[29] as usize // $sp
+    }
+
+    fn instruction_pointer(&self) -> usize {
+        self.0[31] as usize // $ra
+    }
+
+    fn frame_pointer(&self) -> usize {
+        self.0[30] as usize // $fp
+    }
 }
 
-#[cfg(any(target_arch = "mips",
-          target_arch = "mipsel"))]
-fn initialize_call_frame(regs: &mut Registers, fptr: InitFn, arg: usize, thunkptr: *mut libc::c_void, sp: *mut usize) {
+#[cfg(all(not(feature = "ucontext"), target_arch = "mips"))]
+fn initialize_call_frame(regs: &mut Registers, fptr: InitFn, arg: usize, thunkptr: *mut libc::c_void, sp: *mut usize,
+                         _stack_lo: usize) {
+    extern "C" { fn rust_bootstrap_green_task(); } // moves s0-s2 into a0/a1/t9, and the transfer into a2
+
     let sp = align_down(sp);
     // sp of mips o32 is 8-byte aligned
     let sp = mut_offset(sp, -2);
@@ -366,19 +1206,76 @@ fn initialize_call_frame(regs: &mut Registers, fptr: InitFn, arg: usize, thunkpt
 
     let &mut Registers(ref mut regs) = regs;
 
-    regs[4] = arg as libc::uintptr_t;
-    regs[5] = thunkptr as libc::uintptr_t;
+    regs[16] = arg as libc::uintptr_t;
+    regs[17] = thunkptr as libc::uintptr_t;
+    regs[18] = fptr as usize as libc::uintptr_t;
     regs[29] = sp as libc::uintptr_t;
-    regs[25] = fptr as libc::uintptr_t;
-    regs[31] = fptr as libc::uintptr_t;
+    regs[25] = rust_bootstrap_green_task as *const () as libc::uintptr_t;
+    regs[31] = rust_bootstrap_green_task as *const () as libc::uintptr_t;
 }
 
+// A ucontext_t, see ucontext/_context.c, which checks that it fits. The C
+// side fixes up the pointers glibc keeps into it before loading it, even
+// through the `*const Registers` of the context to resume.
+#[cfg(feature = "ucontext")]
+#[repr(C, align(16))]
+struct Registers(UnsafeCell<[libc::uintptr_t; 640]>);
+
+#[cfg(feature = "ucontext")]
+impl Registers {
+    fn new() -> Registers {
+        Registers(UnsafeCell::new([0; 640]))
+    }
+
+    fn stack_pointer(&self) -> usize {
+        extern "C" { fn rust_registers_sp(regs: *const Registers) -> usize; }
+
+        unsafe { rust_registers_sp(self) }
+    }
+
+    fn instruction_pointer(&self) -> usize {
+        extern "C" { fn rust_registers_ip(regs: *const Registers) -> usize; }
+
+        unsafe { rust_registers_ip(self) }
+    }
+
+    fn frame_pointer(&self) -> usize {
+        extern "C" { fn rust_registers_fp(regs: *const Registers) -> usize; }
+
+        unsafe { rust_registers_fp(self) }
+    }
+}
+
+// The contents are whatever the C library put there
+#[cfg(feature = "ucontext")]
+impl fmt::Debug for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Registers {{ .. }}")
+    }
+}
+
+#[cfg(feature = "ucontext")]
+fn initialize_call_frame(regs: &mut Registers, fptr: InitFn, arg: usize, thunkptr: *mut libc::c_void, sp: *mut usize,
+                         stack_lo: usize) {
+    // getcontext and makecontext, with a bootstrap function passing the
+    // transfer as the third argument. makecontext is told about the whole
+    // stack from `stack_lo` up to `sp`, 0 if its lower end is unknown.
+    extern "C" {
+        fn rust_make_registers(regs: *mut Registers, fptr: InitFn, arg: usize,
+                               thunkptr: *mut libc::c_void, sp: *mut usize, stack_lo: usize);
+    }
+
+    unsafe { rust_make_registers(regs, fptr, arg, thunkptr, sp, stack_lo) };
+}
+
+#[cfg(not(feature = "ucontext"))]
 fn align_down(sp: *mut usize) -> *mut usize {
     let sp = (sp as usize) & !(16 - 1);
     sp as *mut usize
 }
 
 // ptr::mut_offset is positive isizes only
+#[cfg(not(feature = "ucontext"))]
 #[inline]
 fn mut_offset<T>(ptr: *mut T, count: isize) -> *mut T {
     // use std::mem::size_of;
@@ -390,20 +1287,22 @@ fn mut_offset<T>(ptr: *mut T, count: isize) -> *mut T {
 mod test {
     use libc;
 
-    use std::mem::transmute;
+    use std::mem::{self, transmute};
+    use std::ptr;
+    use std::panic::{self, AssertUnwindSafe};
 
-    use stack::Stack;
-    use context::Context;
+    use stack::{HeapStack, SharedStack, Stack, StackBuilder, StackSource};
+    use context::{Context, Transfer, current_context, current_stack_bounds, remaining_stack};
 
     const MIN_STACK: usize = 2 * 1024 * 1024;
 
     extern "C" fn init_fn(arg: usize, f: *mut libc::c_void) -> ! {
         let func: fn() = unsafe {
-            transmute(f)
+            transmute::<*mut libc::c_void, fn()>(f)
         };
         func();
 
-        let ctx: &Context = unsafe { transmute(arg) };
+        let ctx: &Context = unsafe { &*(arg as *const Context) };
         Context::load(ctx);
 
         unreachable!("Should not come to here");
@@ -416,7 +1315,7 @@ mod test {
         fn callback() {}
 
         let mut stk = Stack::new(MIN_STACK);
-        let ctx = Context::new(init_fn, unsafe { transmute(&cur) }, unsafe { transmute(callback) }, &mut stk);
+        let ctx = Context::new(init_fn, &cur as *const Context as usize, callback as fn() as *mut libc::c_void, &mut stk);
 
         Context::swap(&mut cur, &ctx);
     }
@@ -428,7 +1327,7 @@ mod test {
         fn callback() {}
 
         let mut stk = Stack::new(MIN_STACK);
-        let ctx = Context::new(init_fn, unsafe { transmute(&cur) }, unsafe { transmute(callback) }, &mut stk);
+        let ctx = Context::new(init_fn, &cur as *const Context as usize, callback as fn() as *mut libc::c_void, &mut stk);
 
         let mut _no_use = Box::new(true);
 
@@ -438,4 +1337,409 @@ mod test {
             Context::load(&ctx);
         }
     }
+
+    extern "C" fn echo_init_fn(arg: usize, _: *mut libc::c_void) -> ! {
+        let mut ctx = Context::empty();
+        let mut t = Context::jump(&mut ctx, unsafe { &*(arg as *const Context) }, 1);
+        while t.data != 0 {
+            t = Context::jump(&mut ctx, unsafe { &*t.from }, t.data + 1);
+        }
+        Context::load(unsafe { &*t.from });
+
+        unreachable!("Should not come to here");
+    }
+
+    #[test]
+    fn test_jump_context() {
+        let mut cur = Context::empty();
+
+        let mut stk = Stack::new(MIN_STACK);
+        let ctx = Context::new(echo_init_fn, &cur as *const Context as usize, ptr::null_mut(), &mut stk);
+
+        let t: Transfer = Context::jump(&mut cur, &ctx, 0);
+        assert_eq!(t.data, 1);
+        let t = Context::jump(&mut cur, unsafe { &*t.from }, 10);
+        assert_eq!(t.data, 11);
+        let t = Context::jump(&mut cur, unsafe { &*t.from }, 20);
+        assert_eq!(t.data, 21);
+        let t = Context::jump(&mut cur, unsafe { &*t.from }, 0);
+        assert!(t.from.is_null());
+        assert_eq!(t.data, 0);
+    }
+
+    #[test]
+    fn test_closure_context() {
+        let mut cur = Context::empty();
+
+        let mut stk = Stack::new(MIN_STACK);
+        let greeting = String::from("hello");
+        let ctx = Context::with_closure(&mut stk, move |t| {
+            let mut me = Context::empty();
+            let t = Context::jump(&mut me, unsafe { &*t.from }, t.data + greeting.len());
+            Transfer::new(t.from, t.data * 2)
+        });
+
+        let t = Context::jump(&mut cur, &ctx, 1);
+        assert_eq!(t.data, 6);
+        let t = Context::jump(&mut cur, unsafe { &*t.from }, 21);
+        assert_eq!(t.data, 42);
+    }
+
+    #[test]
+    fn test_raw_parts() {
+        extern "C" fn add(a: usize, b: usize, t: *const Transfer) -> ! {
+            let mut me = Context::empty();
+            let t = unsafe { &*t };
+            Context::jump(&mut me, unsafe { &*t.from }, a + b + t.data);
+            unreachable!("Should not come to here");
+        }
+
+        let mut cur = Context::empty();
+        assert_eq!(cur.stack_pointer(), 0);
+
+        let stk = Stack::new(MIN_STACK);
+        let ctx = unsafe {
+            Context::from_raw_parts(add as *const () as usize, stk.end() as usize, [30, 10])
+        };
+        assert!(ctx.instruction_pointer() != 0);
+
+        let t = Context::jump(&mut cur, &ctx, 2);
+        assert_eq!(t.data, 42);
+
+        // Suspended inside `add`, somewhere on its stack
+        let sp = unsafe { (*t.from).stack_pointer() };
+        if cfg!(not(feature = "ucontext")) || sp != 0 {
+            assert!(sp > stk.start() as usize && sp < stk.end() as usize);
+            assert!(unsafe { (*t.from).instruction_pointer() } != 0);
+        }
+    }
+
+    #[test]
+    fn test_stack_sources() {
+        fn run_on<S: StackSource>(stk: &mut S) {
+            let mut cur = Context::empty();
+            let ctx = Context::with_closure(stk, |t| Transfer::new(t.from, t.data + 1));
+            assert_eq!(Context::jump(&mut cur, &ctx, 41).data, 42);
+        }
+
+        run_on(&mut HeapStack::new(MIN_STACK));
+        let mut stk: &'static mut [u8] = Box::leak(vec![0; MIN_STACK].into_boxed_slice());
+        run_on(&mut stk);
+    }
+
+    #[test]
+    fn test_stack_bounds() {
+        let mut cur = Context::empty();
+        assert_eq!(current_stack_bounds(), None);
+
+        let mut stk = Stack::new(MIN_STACK);
+        let bounds = (stk.start() as usize, stk.end() as usize);
+        let ctx = Context::with_closure(&mut stk, move |t| {
+            assert_eq!(current_stack_bounds(), Some(bounds));
+            let left = remaining_stack().unwrap();
+            assert!(left > 0 && left < MIN_STACK);
+
+            let mut me = Context::empty();
+            let t = Context::jump(&mut me, unsafe { &*t.from }, 0);
+            assert_eq!(current_stack_bounds(), Some(bounds));
+            t
+        });
+        assert_eq!(ctx.stack_bounds(), Some(bounds));
+
+        let t = Context::jump(&mut cur, &ctx, 0);
+        assert_eq!(current_stack_bounds(), None);
+        assert_eq!(unsafe { &*t.from }.stack_bounds(), Some(bounds));
+        Context::jump(&mut cur, unsafe { &*t.from }, 0);
+        assert_eq!(current_stack_bounds(), None);
+    }
+
+    #[test]
+    fn test_stack_canary() {
+        let mut cur = Context::empty();
+
+        let mut stk = StackBuilder::new(MIN_STACK).canary(64).build().unwrap();
+        assert_eq!(stk.canary().map(|(_, words)| words), Some(64 / mem::size_of::<usize>()));
+        let ctx = Context::with_closure(&mut stk, |t| {
+            let mut me = Context::empty();
+            Context::jump(&mut me, unsafe { &*t.from }, 0)
+        });
+
+        // Switching away from an intact stack goes on as usual
+        let t = Context::jump(&mut cur, &ctx, 0);
+        Context::jump(&mut cur, unsafe { &*t.from }, 0);
+    }
+
+    // Corrupts the canary and switches away in a child process, which has to
+    // abort with a message instead of carrying on
+    #[test]
+    fn test_stack_canary_corrupted() {
+        use std::env;
+        use std::process::Command;
+
+        if let Some(name) = env::var_os("CONTEXT_CORRUPT_CANARY") {
+            let mut cur = Context::empty();
+
+            let mut builder = StackBuilder::new(MIN_STACK).canary(64);
+            if !name.is_empty() {
+                builder = builder.name(name.into_string().unwrap());
+            }
+            let mut stk = builder.build().unwrap();
+            let (canary, _) = stk.canary().unwrap();
+            let ctx = Context::with_closure(&mut stk, move |t| {
+                unsafe { ptr::write_volatile(canary as *mut usize, 0) };
+                let mut me = Context::empty();
+                Context::jump(&mut me, unsafe { &*t.from }, 0)
+            });
+
+            Context::jump(&mut cur, &ctx, 0);
+            unreachable!("the corrupted canary went unnoticed");
+        }
+
+        // A named stack is reported by its name, any other one by its bounds
+        for &(name, report) in &[("", "the canary of the stack at 0x"),
+                                 ("corrupted", "the canary of the stack 'corrupted' at 0x")] {
+            let output = Command::new(env::current_exe().unwrap())
+                             .args(["context::test::test_stack_canary_corrupted", "--exact", "--nocapture"])
+                             .env("CONTEXT_CORRUPT_CANARY", name)
+                             .output()
+                             .unwrap();
+            assert!(!output.status.success());
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            assert!(stderr.contains(&format!("fiber stack overflow: {}", report)), "{}", stderr);
+            assert!(!stderr.contains("went unnoticed"), "{}", stderr);
+        }
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos", windows))]
+    #[test]
+    fn test_current_context() {
+        let mut cur = Context::current();
+        let (lo, hi) = cur.stack_bounds().unwrap();
+        let marker = 0u8;
+        assert!(lo < &marker as *const u8 as usize && hi > &marker as *const u8 as usize);
+        assert_eq!(current_stack_bounds(), Some((lo, hi)));
+        assert!(remaining_stack().is_some());
+        assert_eq!(current_context(), None);
+
+        let mut stk = Stack::new(MIN_STACK);
+        let ctx = Context::with_closure(&mut stk, |t| {
+            assert_eq!(current_context().map(|id| id.0), Some(t.data));
+
+            let mut me = Context::empty();
+            let t = Context::jump(&mut me, unsafe { &*t.from }, 0);
+            assert_eq!(current_context().map(|id| id.0), Some(t.data));
+            t
+        });
+        assert!(ctx.id().is_some());
+        assert_eq!(cur.id(), None);
+
+        let t = Context::jump(&mut cur, &ctx, ctx.id().unwrap().0);
+        assert_eq!(current_context(), None);
+        assert_eq!(current_stack_bounds(), Some((lo, hi)));
+        // Suspended in another context, but still the same fiber
+        assert_eq!(unsafe { (*t.from).id() }, ctx.id());
+        Context::jump(&mut cur, unsafe { &*t.from }, ctx.id().unwrap().0);
+        assert_eq!(current_context(), None);
+    }
+
+    #[test]
+    fn test_fiber_identity() {
+        let mut cur = Context::empty();
+
+        // Hands back its identity to whoever resumes it, twice
+        let mut stk_b = Stack::new(MIN_STACK);
+        let ctx_b = Context::with_closure(&mut stk_b, |t| {
+            let b = current_context().unwrap();
+            let mut me = Context::empty();
+            let t = Context::jump(&mut me, unsafe { &*t.from }, b.0);
+            assert_eq!(current_context(), Some(b));
+            Transfer::new(t.from, b.0)
+        });
+        let b = ctx_b.id().unwrap();
+
+        // Switches to B and back, making sure it is still A afterwards
+        let mut stk_a = Stack::new(MIN_STACK);
+        let ctx_a = Context::with_closure(&mut stk_a, move |t| {
+            let a = current_context().unwrap();
+            let mut me = Context::empty();
+            let tb = Context::jump(&mut me, unsafe { &*(t.data as *const Context) }, 0);
+            assert_eq!((current_context(), tb.data), (Some(a), b.0));
+            let tb = Context::jump(&mut me, unsafe { &*tb.from }, 0);
+            assert_eq!((current_context(), tb.data), (Some(a), b.0));
+            Transfer::new(t.from, a.0)
+        });
+        let a = ctx_a.id().unwrap();
+        assert!(a != b);
+
+        let t = Context::jump(&mut cur, &ctx_a, &ctx_b as *const Context as usize);
+        assert_eq!(t.data, a.0);
+        assert_eq!(current_context(), None);
+
+        let raw = unsafe { Context::from_raw_parts(0, stk_a.end() as usize, [0, 0]) };
+        assert!(raw.id().is_some() && raw.id() != Some(a) && raw.id() != Some(b));
+    }
+
+    #[test]
+    fn test_shared_stack() {
+        use std::hint;
+
+        // Leaves some data on the stack at every level, and switches back to
+        // `from` at the deepest one
+        fn fill(me: &mut Context, from: &mut *mut Context, seed: usize, depth: usize) -> usize {
+            // Kept on the stack even by the optimizer
+            let data = hint::black_box([seed + depth; 64]);
+            if depth > 0 {
+                fill(me, from, seed, depth - 1) + data.iter().sum::<usize>()
+            } else {
+                *from = Context::jump(me, unsafe { &**from }, seed).from;
+                data.iter().sum()
+            }
+        }
+
+        let mut cur = Context::empty();
+
+        let mut shared = SharedStack::new(Stack::new(MIN_STACK));
+        let ctxs: Vec<Context> = (0..3).map(|seed| {
+            Context::with_closure(&mut shared, move |t| {
+                // Out of the way of the stack, which others run on as well
+                let mut me = Box::new(Context::empty());
+                let mut from = t.from;
+                let sum = fill(&mut me, &mut from, seed, 10);
+                Transfer::new(from, sum)
+            })
+        }).collect();
+
+        let mut suspended = Vec::new();
+        for (seed, ctx) in ctxs.iter().enumerate() {
+            let t = Context::jump(&mut cur, ctx, 0);
+            assert_eq!(t.data, seed);
+            let ctx = unsafe { &*t.from };
+            assert!(ctx.saved.0.len() > 10 * 64 * mem::size_of::<usize>());
+            assert!(ctx.saved.0.len() < MIN_STACK / 16);
+            suspended.push(t.from);
+        }
+
+        // Every context gets its own stack back, also with a function run on
+        // top of it
+        fn pass(t: Transfer) -> Transfer {
+            t
+        }
+        for (seed, &ctx) in suspended.iter().enumerate().rev() {
+            let t = if seed == 1 {
+                Context::swap_ontop(&mut cur, unsafe { &*ctx }, 0, pass)
+            } else {
+                Context::jump(&mut cur, unsafe { &*ctx }, 0)
+            };
+            assert_eq!(t.data, 64 * (11 * seed + 55));
+        }
+    }
+
+    #[cfg(all(not(windows), target_arch = "x86_64"))]
+    #[test]
+    #[allow(deprecated)]
+    fn test_fp_control_word() {
+        use std::arch::asm;
+        use std::hint;
+        use std::arch::x86_64::{_mm_getcsr, _mm_setcsr, _MM_ROUND_MASK, _MM_ROUND_TOWARD_ZERO};
+
+        fn x87_control_word() -> u16 {
+            let mut cw = 0u16;
+            unsafe { asm!("fnstcw [{}]", in(reg) &mut cw, options(nostack)) };
+            cw
+        }
+
+        // Moves the new context out of the frame it got made in, which is
+        // wiped afterwards
+        #[inline(never)]
+        fn make<F>(stk: &mut Stack, f: F) -> Box<Context>
+            where F: FnOnce(Transfer) -> Transfer + 'static
+        {
+            Box::new(Context::with_closure(stk, f))
+        }
+
+        #[inline(never)]
+        fn wipe() {
+            hint::black_box([0u8; 64 * 1024]);
+        }
+
+        let mut cur = Context::empty();
+
+        let mut stk = Stack::new(MIN_STACK);
+        let ctx = make(&mut stk, |t| {
+            let mut me = Context::empty();
+            let csr = unsafe { _mm_getcsr() };
+            unsafe { _mm_setcsr(csr & !_MM_ROUND_MASK | _MM_ROUND_TOWARD_ZERO) };
+            let words = (x87_control_word() as usize) << 32 | csr as usize;
+            let t = Context::jump(&mut me, unsafe { &*t.from }, words);
+            Transfer::new(t.from, unsafe { _mm_getcsr() } as usize)
+        });
+
+        wipe();
+
+        let csr = unsafe { _mm_getcsr() };
+        // A new context starts with the default ones, whether it got moved
+        // after being made or not
+        let t = Context::jump(&mut cur, &ctx, 0);
+        assert_eq!(t.data, 0x037F_0000_1F80);
+        assert_eq!(unsafe { _mm_getcsr() }, csr);
+        let t = Context::jump(&mut cur, unsafe { &*t.from }, 0);
+        assert_eq!(t.data as u32 & _MM_ROUND_MASK, _MM_ROUND_TOWARD_ZERO);
+    }
+
+    #[test]
+    fn test_ontop_context() {
+        fn times_ten(t: Transfer) -> Transfer {
+            Transfer::new(t.from, t.data * 10)
+        }
+
+        let mut cur = Context::empty();
+
+        let mut stk = Stack::new(MIN_STACK);
+        let ctx = Context::with_closure(&mut stk, |t| {
+            let mut me = Context::empty();
+            let t = Context::jump(&mut me, unsafe { &*t.from }, t.data + 1);
+            Transfer::new(t.from, t.data + 1)
+        });
+
+        // Runs on top of a fresh context as well
+        let t = Context::swap_ontop(&mut cur, &ctx, 1, times_ten);
+        assert_eq!(t.data, 11);
+        let t = Context::swap_ontop(&mut cur, unsafe { &*t.from }, 2, times_ten);
+        assert_eq!(t.data, 21);
+    }
+
+    #[test]
+    fn test_ontop_unwind() {
+        struct Cancel;
+
+        fn cancel(_: Transfer) -> Transfer {
+            panic::resume_unwind(Box::new(Cancel))
+        }
+
+        let mut cur = Context::empty();
+
+        let mut stk = Stack::new(MIN_STACK);
+        let ctx = Context::with_closure(&mut stk, |t| {
+            let mut me = Context::empty();
+            let parent = t.from;
+            let ret = panic::catch_unwind(AssertUnwindSafe(|| {
+                let mut t = t;
+                loop {
+                    t = Context::jump(&mut me, unsafe { &*t.from }, t.data + 1);
+                }
+            }));
+            let cancelled = match ret {
+                Err(err) => err.is::<Cancel>(),
+                Ok(()) => false,
+            };
+            Transfer::new(parent, if cancelled { 42 } else { 0 })
+        });
+
+        let t = Context::jump(&mut cur, &ctx, 1);
+        assert_eq!(t.data, 2);
+        let t = Context::jump(&mut cur, unsafe { &*t.from }, 2);
+        assert_eq!(t.data, 3);
+        let t = Context::swap_ontop(&mut cur, unsafe { &*t.from }, 0, cancel);
+        assert_eq!(t.data, 42);
+    }
 }
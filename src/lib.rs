@@ -1,15 +1,13 @@
-#![feature(asm, repr_simd)]
-
 #[macro_use]
 extern crate log;
 extern crate libc;
-extern crate memmap;
 
-pub use context::Context;
-pub use stack::Stack;
+pub use context::{Context, FiberId, Transfer};
+pub use stack::{Stack, StackError, StackSource, StackAllocator};
 
 pub mod context;
 pub mod stack;
+pub mod overflow;
 mod sys;
-#[cfg(target_arch = "x86_64")]
-mod simd;
+#[cfg(all(feature = "global-asm", not(feature = "ucontext")))]
+mod asm;
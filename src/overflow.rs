@@ -0,0 +1,602 @@
+// Tells a fiber overflowing its stack apart from any other segfault.
+//
+// Once `install_handler` has run, every `Stack` with guard pages at its low
+// end notes them in a registry. The handler looks the faulting address up in
+// there, and reports which stack overflowed before aborting, much like the
+// "thread has overflowed its stack" of the standard library. Faults anywhere
+// else are handed on to whatever handler was installed before. A stack with
+// a canary is noted regardless, its name goes into the report of a canary
+// which got overwritten.
+//
+// The registry is a list of chunks of slots which is never freed, so the
+// handler can walk it without taking a lock, and stacks come and go with a
+// few atomic operations on their slot.
+//
+// If the stack is the one of a context resumed by `Context::try_jump`
+// instead, the handler doesn't abort but makes the thread go on in
+// `context::overflow_landing_pad`, on an emergency area set aside for it, which
+// then switches back to where `Context::try_jump` was called.
+
+use std::array;
+use std::cell::{Cell, RefCell};
+use std::error;
+use std::fmt;
+use std::io;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+
+use context::Context;
+use stack::Stack;
+
+// Set once the handler is installed, no stack is registered before
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+// The guard pages of a stack, and the stack above them
+#[derive(Debug)]
+struct Slot {
+    state: AtomicUsize,
+    guard: AtomicUsize,
+    end: AtomicUsize,
+    lo: AtomicUsize,
+    hi: AtomicUsize,
+    // A leaked Box<String>, or null. Only the owner of the registration
+    // changes it, and the handler only reads the one of the stack which
+    // overflowed, which its owner can't be busy renaming or dropping.
+    name: AtomicPtr<String>,
+}
+
+const FREE: usize = 0;
+const TAKEN: usize = 1;
+const LIVE: usize = 2;
+
+const CHUNK_SLOTS: usize = 64;
+
+struct Chunk {
+    slots: [Slot; CHUNK_SLOTS],
+    // Set before the chunk is published, never changed afterwards
+    next: *const Chunk,
+}
+
+static CHUNKS: AtomicPtr<Chunk> = AtomicPtr::new(ptr::null_mut());
+
+impl Slot {
+    fn new() -> Slot {
+        Slot {
+            state: AtomicUsize::new(FREE),
+            guard: AtomicUsize::new(0),
+            end: AtomicUsize::new(0),
+            lo: AtomicUsize::new(0),
+            hi: AtomicUsize::new(0),
+            name: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    fn stack(&self) -> (usize, usize) {
+        (self.lo.load(Ordering::Relaxed), self.hi.load(Ordering::Relaxed))
+    }
+
+    // Safe to call by the owner of the registration, or for the stack which
+    // overflowed
+    unsafe fn name(&self) -> Option<&str> {
+        self.name.load(Ordering::Acquire).as_ref().map(|s| &s[..])
+    }
+
+    fn set_name(&self, name: Option<String>) {
+        let new = name.map_or(ptr::null_mut(), |name| Box::into_raw(Box::new(name)));
+        let old = self.name.swap(new, Ordering::AcqRel);
+        if !old.is_null() {
+            drop(unsafe { Box::from_raw(old) });
+        }
+    }
+}
+
+// All the slots there are, without blocking
+fn slots() -> impl Iterator<Item = &'static Slot> {
+    let mut chunk = CHUNKS.load(Ordering::Acquire) as *const Chunk;
+    let chunks = ::std::iter::from_fn(move || {
+        let current = unsafe { chunk.as_ref() }?;
+        chunk = current.next;
+        Some(current)
+    });
+    chunks.flat_map(|chunk| chunk.slots.iter())
+}
+
+// A free slot, taken for the caller
+fn take_slot() -> &'static Slot {
+    loop {
+        let free = slots().find(|slot| {
+            slot.state.compare_exchange(FREE, TAKEN, Ordering::Acquire, Ordering::Relaxed).is_ok()
+        });
+        if let Some(slot) = free {
+            return slot;
+        }
+
+        // All taken, add a chunk with the first slot taken already
+        let head = CHUNKS.load(Ordering::Acquire);
+        let chunk = Box::into_raw(Box::new(Chunk {
+            slots: array::from_fn(|_| Slot::new()),
+            next: head,
+        }));
+        unsafe { (*chunk).slots[0].state.store(TAKEN, Ordering::Relaxed) };
+        match CHUNKS.compare_exchange(head, chunk, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => return unsafe { &(*chunk).slots[0] },
+            // Someone else added one, which may have a free slot for us
+            Err(_) => drop(unsafe { Box::from_raw(chunk) }),
+        }
+    }
+}
+
+// The registered stack whose guard pages `addr` is in
+fn find(addr: usize) -> Option<&'static Slot> {
+    slots().find(|slot| {
+        slot.state.load(Ordering::Acquire) == LIVE &&
+            slot.guard.load(Ordering::Relaxed) <= addr && addr < slot.end.load(Ordering::Relaxed)
+    })
+}
+
+// The name of the registered stack ending at `hi`, for the stack of a
+// context which is running or about to be switched away from
+pub(crate) fn name_of(hi: usize) -> Option<String> {
+    slots().find(|slot| slot.state.load(Ordering::Acquire) == LIVE && slot.hi.load(Ordering::Relaxed) == hi)
+           .and_then(|slot| unsafe { slot.name() }.map(|name| name.to_owned()))
+}
+
+// Notes the guard pages of a stack until dropped
+#[derive(Debug)]
+pub(crate) struct Registration {
+    slot: &'static Slot,
+}
+
+impl Registration {
+    // Note the guard pages at `guard`, of `len` bytes, below the stack
+    // spanning `stack`, if the handler has been installed or the stack is
+    // to be registered `always`, e.g. for the name of a stack with a canary
+    pub(crate) fn new(guard: usize, len: usize, stack: (usize, usize), name: Option<String>,
+                      always: bool) -> Option<Registration> {
+        if !always && !INSTALLED.load(Ordering::Acquire) {
+            return None;
+        }
+
+        let slot = take_slot();
+        slot.guard.store(guard, Ordering::Relaxed);
+        slot.end.store(guard + len, Ordering::Relaxed);
+        slot.lo.store(stack.0, Ordering::Relaxed);
+        slot.hi.store(stack.1, Ordering::Relaxed);
+        slot.set_name(name);
+        slot.state.store(LIVE, Ordering::Release);
+        Some(Registration {
+            slot,
+        })
+    }
+
+    pub(crate) fn set_name(&self, name: Option<String>) {
+        self.slot.set_name(name);
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.slot.state.store(TAKEN, Ordering::Release);
+        self.slot.set_name(None);
+        self.slot.state.store(FREE, Ordering::Release);
+    }
+}
+
+/// A context resumed by `Context::try_jump` has overflowed its stack
+///
+/// Nothing on that stack has been dropped, and the context must not be
+/// resumed again. Its stack may be dropped or reused.
+#[derive(Debug, Clone)]
+pub struct StackOverflow {
+    bounds: (usize, usize),
+    name: Option<String>,
+}
+
+impl StackOverflow {
+    /// The lower and upper bound of the stack which overflowed
+    pub fn bounds(&self) -> (usize, usize) {
+        self.bounds
+    }
+
+    /// The name of the stack, see `StackBuilder::name`
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_ref().map(|s| &s[..])
+    }
+}
+
+impl fmt::Display for StackOverflow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (lo, hi) = self.bounds;
+        match self.name {
+            Some(ref name) => write!(f, "fiber stack overflow on the stack '{}' at {:#x}-{:#x}", name, lo, hi),
+            None => write!(f, "fiber stack overflow on the stack at {:#x}-{:#x}", lo, hi),
+        }
+    }
+}
+
+impl error::Error for StackOverflow {}
+
+// Where `Context::try_jump` waits for the context it resumed
+#[derive(Copy, Clone)]
+pub(crate) struct Recovery {
+    pub(crate) to: *mut Context,
+    // The upper bound of the stack the resumed context runs on
+    pub(crate) stack_hi: usize,
+}
+
+// Large enough for the landing pad, which only switches away again
+const EMERGENCY_SIZE: usize = 64 * 1024;
+
+thread_local! {
+    static RECOVERY: Cell<Option<Recovery>> = const { Cell::new(None) };
+    // The area the landing pad runs on, and its upper end for the handler
+    static EMERGENCY: RefCell<Option<Stack>> = const { RefCell::new(None) };
+    static EMERGENCY_TOP: Cell<usize> = const { Cell::new(0) };
+    // Set by the handler before the thread lands, the bounds of the stack
+    static FAULTED: Cell<(usize, usize)> = const { Cell::new((0, 0)) };
+    static OVERFLOWED: RefCell<Option<StackOverflow>> = const { RefCell::new(None) };
+}
+
+// Make `recovery` the one to land in, returns the one before
+pub(crate) fn set_recovery(recovery: Option<Recovery>) -> Option<Recovery> {
+    if recovery.is_some() && EMERGENCY_TOP.with(|top| top.get()) == 0 {
+        let stack = Stack::new(EMERGENCY_SIZE);
+        EMERGENCY_TOP.with(|top| top.set(stack.end() as usize));
+        EMERGENCY.with(|emergency| *emergency.borrow_mut() = Some(stack));
+    }
+    RECOVERY.with(|r| r.replace(recovery))
+}
+
+// Called by the landing pad, notes the overflow for `take_overflow` and
+// returns the context to switch back to
+pub(crate) fn land() -> *mut Context {
+    let bounds = FAULTED.with(|f| f.get());
+    let name = name_of(bounds.1);
+    OVERFLOWED.with(|o| *o.borrow_mut() = Some(StackOverflow { bounds, name }));
+    RECOVERY.with(|r| r.get()).expect("landed without a Context::try_jump").to
+}
+
+pub(crate) fn take_overflow() -> Option<StackOverflow> {
+    OVERFLOWED.with(|o| o.borrow_mut().take())
+}
+
+/// Install a `SIGSEGV` and `SIGBUS` handler which recognises a fiber
+/// overflowing into the guard pages of its `Stack`
+///
+/// The handler prints "fiber stack overflow" with the address, size and name
+/// of the stack, see `StackBuilder::name`, and aborts the process. Any other
+/// fault is passed on to the handler installed before, e.g. the one of the
+/// standard library. Only the stacks allocated after the handler got
+/// installed are known to it, or named afterwards with `Stack::set_name`.
+///
+/// The handler runs on an alternate signal stack, as the one which overflowed
+/// is of no use anymore. Threads spawned by the standard library have got one
+/// already, for any other thread fibers run on this has to be called once on
+/// that thread, which then sets one up. Calling it again is harmless.
+///
+/// Fails on platforms other than Linux and macOS.
+pub fn install_handler() -> io::Result<()> {
+    imp::install_handler()
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+mod imp {
+    use std::cell::RefCell;
+    use std::fmt::{self, Write};
+    use std::io;
+    use std::mem;
+    use std::process;
+    use std::ptr;
+    use std::sync::OnceLock;
+
+    use libc;
+
+    use stack::Stack;
+    use context;
+    use sys::signal::{self, sigaction, siginfo_t, stack_t};
+    use std::sync::atomic::Ordering;
+
+    use super::{find, EMERGENCY_TOP, FAULTED, INSTALLED, RECOVERY};
+
+    const SIGNALS: [libc::c_int; 2] = [signal::SIGSEGV, signal::SIGBUS];
+
+    // Large enough for the handler, which doesn't do a lot
+    const ALT_STACK_SIZE: usize = 64 * 1024;
+
+    // The handlers installed before ours, by signal in `SIGNALS`
+    static mut PREVIOUS: [Option<sigaction>; 2] = [None, None];
+
+    // The alternate signal stack set up by `install_handler`, if the thread
+    // had none
+    struct AltStack {
+        _stack: Stack,
+    }
+
+    impl Drop for AltStack {
+        fn drop(&mut self) {
+            let mut ss: stack_t = unsafe { mem::zeroed() };
+            ss.ss_flags = signal::SS_DISABLE;
+            ss.ss_size = ALT_STACK_SIZE as libc::size_t;
+            unsafe { signal::sigaltstack(&ss, ptr::null_mut()) };
+        }
+    }
+
+    thread_local!(static ALT_STACK: RefCell<Option<AltStack>> = const { RefCell::new(None) });
+
+    pub fn install_handler() -> io::Result<()> {
+        // The errno of the first attempt if it failed, which is what every
+        // later call reports as well
+        static ACTION: OnceLock<Result<(), i32>> = OnceLock::new();
+
+        if let Err(errno) = *ACTION.get_or_init(|| unsafe { install_action() }) {
+            return Err(io::Error::from_raw_os_error(errno));
+        }
+        INSTALLED.store(true, Ordering::Release);
+
+        unsafe { install_alt_stack() }
+    }
+
+    unsafe fn install_action() -> Result<(), i32> {
+        let mut action: sigaction = mem::zeroed();
+        action.sa_sigaction = handle_fault as extern "C" fn(libc::c_int, *mut siginfo_t, *mut libc::c_void)
+                                  as usize;
+        action.sa_flags = signal::SA_SIGINFO | signal::SA_ONSTACK;
+
+        let previous = &mut *ptr::addr_of_mut!(PREVIOUS);
+        for (&signum, previous) in SIGNALS.iter().zip(previous.iter_mut()) {
+            let mut old: sigaction = mem::zeroed();
+            if signal::sigaction(signum, &action, &mut old) == -1 {
+                return Err(io::Error::last_os_error().raw_os_error().unwrap_or(0));
+            }
+            *previous = Some(old);
+        }
+        Ok(())
+    }
+
+    unsafe fn install_alt_stack() -> io::Result<()> {
+        let mut current: stack_t = mem::zeroed();
+        if signal::sigaltstack(ptr::null(), &mut current) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        if current.ss_flags & signal::SS_DISABLE == 0 {
+            return Ok(());
+        }
+
+        let stack = Stack::try_new(ALT_STACK_SIZE).map_err(|e| io::Error::other(e.to_string()))?;
+        let ss = stack_t {
+            ss_sp: stack.guard() as *mut libc::c_void,
+            ss_size: (stack.end() as usize - stack.guard() as usize) as libc::size_t,
+            ss_flags: 0,
+        };
+        if signal::sigaltstack(&ss, ptr::null_mut()) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        ALT_STACK.with(|alt| *alt.borrow_mut() = Some(AltStack { _stack: stack }));
+        Ok(())
+    }
+
+    extern "C" fn handle_fault(signum: libc::c_int, info: *mut siginfo_t, context: *mut libc::c_void) {
+        let addr = unsafe { (*info).si_addr } as usize;
+
+        if let Some(slot) = find(addr) {
+            let stack = slot.stack();
+            if unsafe { recover(stack, context) } {
+                return;
+            }
+
+            let (lo, hi) = stack;
+            let mut msg = Message { buf: [0; 512], len: 0 };
+            let _ = match unsafe { slot.name() } {
+                Some(name) => writeln!(msg, "fiber stack overflow: the stack '{}' at {:#x}-{:#x} \
+                                            ({} bytes) hit its guard page, aborting",
+                                       name, lo, hi, hi - lo),
+                None => writeln!(msg, "fiber stack overflow: the stack at {:#x}-{:#x} \
+                                       ({} bytes) hit its guard page, aborting",
+                                 lo, hi, hi - lo),
+            };
+            unsafe { libc::write(2, msg.buf.as_ptr() as *const libc::c_void, msg.len as libc::size_t) };
+            process::abort();
+        }
+
+        unsafe { call_previous(signum, info, context) }
+    }
+
+    // Make the thread go on in the landing pad if `stack` is the one of the
+    // context resumed by the innermost `Context::try_jump`
+    unsafe fn recover(stack: (usize, usize), context: *mut libc::c_void) -> bool {
+        let top = EMERGENCY_TOP.with(|top| top.get());
+        match RECOVERY.with(|r| r.get()) {
+            Some(recovery) if recovery.stack_hi == stack.1 && top != 0 => {
+                FAULTED.with(|f| f.set(stack));
+                redirect(context, context::overflow_landing_pad as *const () as usize, top)
+            }
+            _ => false,
+        }
+    }
+
+    // Rewrite the machine state saved in the ucontext_t at `context` to call
+    // the function at `pc` on a stack ending at `top`
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    unsafe fn redirect(context: *mut libc::c_void, pc: usize, top: usize) -> bool {
+        // uc_mcontext.gregs, REG_RSP and REG_RIP
+        let gregs = (context as *mut u8).add(40) as *mut usize;
+        // As if called, with a 0 return address
+        let sp = (top & !15) - 8;
+        *(sp as *mut usize) = 0;
+        *gregs.add(15) = sp;
+        *gregs.add(16) = pc;
+        true
+    }
+
+    #[cfg(all(target_os = "linux", target_arch = "x86"))]
+    unsafe fn redirect(context: *mut libc::c_void, pc: usize, top: usize) -> bool {
+        // uc_mcontext.gregs, REG_ESP and REG_EIP
+        let gregs = (context as *mut u8).add(20) as *mut usize;
+        let sp = (top & !15) - 4;
+        *(sp as *mut usize) = 0;
+        *gregs.add(7) = sp;
+        *gregs.add(14) = pc;
+        true
+    }
+
+    #[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+    unsafe fn redirect(context: *mut libc::c_void, pc: usize, top: usize) -> bool {
+        // uc_mcontext.regs, sp and pc follow each other
+        let regs = (context as *mut u8).add(184) as *mut usize;
+        *regs.add(29) = 0; // x29, last frame pointer
+        *regs.add(30) = 0; // x30, lr
+        *regs.add(31) = top & !15;
+        *regs.add(32) = pc;
+        true
+    }
+
+    // Where the layout of the machine state isn't known the overflow is
+    // reported as usual
+    #[cfg(not(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "x86",
+                                             target_arch = "aarch64"))))]
+    unsafe fn redirect(_context: *mut libc::c_void, _pc: usize, _top: usize) -> bool {
+        false
+    }
+
+    // Pass a fault which isn't ours on
+    unsafe fn call_previous(signum: libc::c_int, info: *mut siginfo_t, context: *mut libc::c_void) {
+        let previous = &*ptr::addr_of!(PREVIOUS);
+        let previous = SIGNALS.iter().position(|&s| s == signum).and_then(|idx| previous[idx].as_ref());
+
+        match previous {
+            Some(action) if action.sa_sigaction != signal::SIG_DFL && action.sa_sigaction != signal::SIG_IGN => {
+                if action.sa_flags & signal::SA_SIGINFO != 0 {
+                    let f: extern "C" fn(libc::c_int, *mut siginfo_t, *mut libc::c_void) =
+                        mem::transmute(action.sa_sigaction);
+                    f(signum, info, context);
+                } else {
+                    let f: extern "C" fn(libc::c_int) = mem::transmute(action.sa_sigaction);
+                    f(signum);
+                }
+            }
+            // Once we return, the faulting instruction runs again and the
+            // default action kills the process
+            _ => {
+                let mut action: sigaction = mem::zeroed();
+                action.sa_sigaction = signal::SIG_DFL;
+                signal::sigaction(signum, &action, ptr::null_mut());
+            }
+        }
+    }
+
+    // Formats the report without allocating, which is not safe in a signal
+    // handler
+    struct Message {
+        buf: [u8; 512],
+        len: usize,
+    }
+
+    impl Write for Message {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            let n = s.len().min(self.buf.len() - self.len);
+            self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+            self.len += n;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+mod imp {
+    use std::io;
+
+    pub fn install_handler() -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "stack overflow handler not supported on this platform"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::hint;
+    use std::process::Command;
+
+    use context::{Context, Transfer};
+    use stack::StackBuilder;
+    use super::find;
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    #[test]
+    fn test_registration() {
+        super::install_handler().unwrap();
+
+        let mut stack = StackBuilder::new(64 * 1024).name("named").build().unwrap();
+        let guard = stack.start() as usize;
+        let slot = find(guard).unwrap();
+        assert_eq!(slot.stack(), (stack.guard() as usize, stack.end() as usize));
+        assert_eq!(unsafe { slot.name() }, Some("named"));
+
+        stack.set_name(None);
+        assert_eq!(unsafe { find(guard).unwrap().name() }, None);
+
+        drop(stack);
+        assert!(find(guard).is_none());
+    }
+
+    // Overflows a named stack in a child process, which has to report it
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    #[test]
+    fn test_overflow_reported() {
+        #[allow(unconditional_recursion)]
+        fn recurse(depth: usize) -> usize {
+            let data = hint::black_box([depth; 64]);
+            recurse(depth + 1) + data[0]
+        }
+
+        if env::var_os("CONTEXT_OVERFLOW_STACK").is_some() {
+            super::install_handler().unwrap();
+
+            let mut cur = Context::empty();
+            let mut stack = StackBuilder::new(64 * 1024).name("overflowing").build().unwrap();
+            let ctx = Context::with_closure(&mut stack, |t| Transfer::new(t.from, recurse(0)));
+            Context::jump(&mut cur, &ctx, 0);
+            unreachable!("the stack didn't overflow");
+        }
+
+        let output = Command::new(env::current_exe().unwrap())
+                         .args(["overflow::test::test_overflow_reported", "--exact", "--nocapture"])
+                         .env("CONTEXT_OVERFLOW_STACK", "1")
+                         .output()
+                         .unwrap();
+        assert!(!output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("fiber stack overflow: the stack 'overflowing' at 0x"), "{}", stderr);
+    }
+
+    #[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "x86", target_arch = "aarch64")))]
+    #[test]
+    fn test_overflow_recovered() {
+        #[allow(unconditional_recursion)]
+        fn recurse(depth: usize) -> usize {
+            let data = hint::black_box([depth; 64]);
+            recurse(depth + 1) + data[0]
+        }
+
+        super::install_handler().unwrap();
+
+        let mut cur = Context::empty();
+        let stack = StackBuilder::new(64 * 1024).name("plugin").build().unwrap();
+        let bounds = (stack.guard() as usize, stack.end() as usize);
+
+        // Twice, the thread has to be able to land again
+        let mut stack = stack;
+        for _ in 0..2 {
+            let ctx = Context::with_closure(&mut stack, |t| Transfer::new(t.from, recurse(0)));
+            let err = Context::try_jump(&mut cur, &ctx, 0).unwrap_err();
+            assert_eq!(err.bounds(), bounds);
+            assert_eq!(err.name(), Some("plugin"));
+        }
+        drop(stack);
+
+        // A context which doesn't overflow is switched to as usual
+        let mut stack = StackBuilder::new(64 * 1024).build().unwrap();
+        let ctx = Context::with_closure(&mut stack, |t| Transfer::new(t.from, t.data + 1));
+        assert_eq!(Context::try_jump(&mut cur, &ctx, 41).unwrap().data, 42);
+        assert!(super::take_overflow().is_none());
+    }
+}
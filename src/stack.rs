@@ -9,159 +9,1638 @@
 // except according to those terms.
 
 use std::ptr;
-use std::sync::atomic;
-use std::env;
+use std::cell::RefCell;
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::error;
 use std::fmt;
+use std::io;
+use std::mem;
 
 use libc;
 
-use memmap::{Mmap, MmapOptions, Protection};
+use overflow::Registration;
 
 /// A task's stack. The name "Stack" is a vestige of segmented stacks.
 pub struct Stack {
-    buf: Option<Mmap>,
+    // Goes before `buf`, so the guard pages are forgotten before they are
+    // unmapped
+    registration: Option<Registration>,
+    buf: Option<StackMemory>,
     min_size: usize,
+    // The bytes protected at the low and at the high end of `buf`
+    low_guard: usize,
+    high_guard: usize,
+    // Whether the unused part of the stack holds the canary
+    painted: bool,
+    // The words of canary right above the low guard pages
+    canary_words: usize,
 }
 
 impl fmt::Debug for Stack {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        try!(write!(f, "Stack {} buf: ", "{"));
+        write!(f, "Stack {{ buf: ")?;
         match self.buf {
-            Some(ref map) => try!(write!(f, "Some({:#x}), ", map.ptr() as libc::uintptr_t)),
-            None => try!(write!(f, "None, ")),
+            Some(ref buf) => write!(f, "Some({:#x}), ", buf.ptr() as libc::uintptr_t)?,
+            None => write!(f, "None, ")?,
         }
-        write!(f, "min_size: {:?} {}", self.min_size, "}")
+        write!(f, "min_size: {:?} }}", self.min_size)
+    }
+}
+
+/// The reasons allocating a `Stack` may fail
+#[derive(Debug)]
+pub enum StackError {
+    /// The requested size is 0 or leaves no room besides the guard pages
+    InvalidSize(usize),
+    /// Mapping in the memory failed, e.g. on ENOMEM or when running into
+    /// `vm.max_map_count`
+    Mmap(io::Error),
+    /// The guard page could not be protected
+    Mprotect(io::Error),
+    /// The requested option is not available on this platform
+    Unsupported(&'static str),
+}
+
+impl fmt::Display for StackError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StackError::InvalidSize(size) =>
+                write!(f, "invalid stack size {}, it has to be larger than the guard pages", size),
+            StackError::Mmap(ref e) => write!(f, "mmap for stack failed: {}", e),
+            StackError::Mprotect(ref e) => write!(f, "could not memory-protect guard page: {}", e),
+            StackError::Unsupported(what) => write!(f, "{} not supported on this platform", what),
+        }
+    }
+}
+
+impl error::Error for StackError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            StackError::InvalidSize(..) | StackError::Unsupported(..) => None,
+            StackError::Mmap(ref e) | StackError::Mprotect(ref e) => Some(e),
+        }
+    }
+}
+
+/// Whether a `Stack` is backed by huge pages
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum HugePages {
+    /// Regular pages only
+    Disabled,
+    /// Ask for transparent huge pages with `madvise`. This is a hint, the
+    /// kernel is free to ignore it.
+    Transparent,
+    /// Map the stack from the hugetlbfs pool with `MAP_HUGETLB`. The size and
+    /// the guard pages are in units of huge pages then, and the allocation
+    /// fails if the pool is exhausted.
+    Explicit,
+}
+
+/// Configures how a `Stack` gets mapped
+///
+/// ```
+/// use context::stack::StackBuilder;
+///
+/// let stack = StackBuilder::new(256 * 1024)
+///     .guard_pages(2)
+///     .high_guard(true)
+///     .populate(true)
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct StackBuilder {
+    size: usize,
+    guard_pages: usize,
+    high_guard: bool,
+    noreserve: bool,
+    populate: bool,
+    huge_pages: HugePages,
+    paint: bool,
+    canary: usize,
+    name: Option<String>,
+}
+
+impl StackBuilder {
+    /// Start configuring a stack of `size` bytes, guard pages included,
+    /// with the defaults of `Stack::new`: a single guard page at the low end,
+    /// committed memory and regular pages which are faulted in on demand.
+    pub fn new(size: usize) -> StackBuilder {
+        StackBuilder {
+            size,
+            guard_pages: 1,
+            high_guard: false,
+            noreserve: false,
+            populate: false,
+            huge_pages: HugePages::Disabled,
+            paint: false,
+            canary: 0,
+            name: None,
+        }
+    }
+
+    /// The number of inaccessible pages at the low end of the stack, where
+    /// an overflow runs into, 0 for none at all
+    pub fn guard_pages(mut self, pages: usize) -> StackBuilder {
+        self.guard_pages = pages;
+        self
+    }
+
+    /// Also put a guard page at the high end of the stack, which catches
+    /// writes running past the top of the stack
+    pub fn high_guard(mut self, enable: bool) -> StackBuilder {
+        self.high_guard = enable;
+        self
+    }
+
+    /// Map the stack with `MAP_NORESERVE`, so no swap space is reserved for
+    /// it. Touching a page may then get the process killed when memory runs
+    /// out. Ignored on Windows.
+    pub fn noreserve(mut self, enable: bool) -> StackBuilder {
+        self.noreserve = enable;
+        self
+    }
+
+    /// Fault in the whole stack up front, with `MAP_POPULATE` on Linux and by
+    /// touching every page elsewhere, so running on it never takes a page
+    /// fault
+    pub fn populate(mut self, enable: bool) -> StackBuilder {
+        self.populate = enable;
+        self
+    }
+
+    /// Back the stack with huge pages, only supported on Linux
+    pub fn huge_pages(mut self, huge_pages: HugePages) -> StackBuilder {
+        self.huge_pages = huge_pages;
+        self
+    }
+
+    /// Paint the stack with a canary, so `Stack::high_water_mark` can tell
+    /// how much of it got used. This touches, and so commits, every page.
+    pub fn paint(mut self, enable: bool) -> StackBuilder {
+        self.paint = enable;
+        self
+    }
+
+    /// Put `bytes` of canary right above the low guard pages, 0 for none,
+    /// which is the default. Switching away from a context checks the canary
+    /// of its stack, and aborts the process if it got overwritten: a large
+    /// frame may well jump over the guard pages, but rarely leaves the
+    /// canary alone.
+    pub fn canary(mut self, bytes: usize) -> StackBuilder {
+        self.canary = bytes;
+        self
+    }
+
+    /// Name the stack in the report of the overflow handler, see
+    /// `overflow::install_handler`, and in the one of a corrupted canary
+    pub fn name<N: Into<String>>(mut self, name: N) -> StackBuilder {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Allocate the stack
+    pub fn build(&self) -> Result<Stack, StackError> {
+        let unit = self.page_size()?;
+        let guards = (self.guard_pages + self.high_guard as usize).saturating_mul(unit);
+        if self.size == 0 || round_up(self.size, unit) <= guards.saturating_add(self.canary) {
+            return Err(StackError::InvalidSize(self.size));
+        }
+        self.map()
+    }
+
+    // The unit the size and the guard pages are measured in
+    fn page_size(&self) -> Result<usize, StackError> {
+        match self.huge_pages {
+            HugePages::Disabled => Ok(page_size()),
+            _ if !cfg!(target_os = "linux") => Err(StackError::Unsupported("huge pages")),
+            HugePages::Transparent => Ok(page_size()),
+            HugePages::Explicit => Ok(huge_page_size()),
+        }
+    }
+
+    // Allocate the stack without checking that there's any room left
+    fn map(&self) -> Result<Stack, StackError> {
+        let unit = self.page_size()?;
+        let len = round_up(self.size, unit);
+        let low_guard = self.guard_pages * unit;
+        let high_guard = if self.high_guard { unit } else { 0 };
+
+        let map = Mapping::new(len, self.noreserve, self.populate,
+                               self.huge_pages == HugePages::Explicit)
+            .map_err(StackError::Mmap)?;
+
+        // Change the last pages to be inaccessible. This is to provide safety;
+        // when an FFI function overflows it will (hopefully) hit this guard
+        // page. It isn't guaranteed, but that's why FFI is unsafe. The mapping
+        // is guaranteed to be aligned properly.
+        unsafe {
+            protect(map.ptr, low_guard).map_err(StackError::Mprotect)?;
+            protect(map.ptr.add(len - high_guard), high_guard).map_err(StackError::Mprotect)?;
+        }
+
+        let usable = unsafe { map.ptr.add(low_guard) };
+        let usable_len = len.saturating_sub(low_guard + high_guard);
+        if self.huge_pages == HugePages::Transparent {
+            // Only a hint, a kernel without them still gives us a stack
+            unsafe { advise_huge_pages(usable, usable_len) };
+        }
+        if self.populate && !cfg!(target_os = "linux") {
+            prefault(usable, usable_len);
+        }
+
+        let mut stack = Stack {
+            registration: None,
+            buf: Some(StackMemory::Mapping(map)),
+            min_size: self.size,
+            low_guard,
+            high_guard,
+            painted: false,
+            canary_words: self.canary.div_ceil(mem::size_of::<usize>()),
+        };
+        for idx in 0..stack.canary_words {
+            unsafe { ptr::write_volatile((stack.guard() as *mut usize).add(idx), CANARY) };
+        }
+        stack.register(self.name.clone());
+        if self.paint {
+            stack.paint();
+        }
+        Ok(stack)
     }
 }
 
 impl Stack {
     /// Allocate a new stack of `size`. If size = 0, this will fail. Use
     /// `dummy_stack` if you want a zero-sized stack.
+    ///
+    /// The lowest page of the stack is a guard page, see `StackBuilder` for
+    /// other layouts.
+    ///
+    /// Panics if the stack can't be allocated, see `Stack::try_new` for the
+    /// fallible version.
     pub fn new(size: usize) -> Stack {
-        // Map in a stack. Eventually we might be able to handle stack
-        // allocation failure, which would fail to spawn the task. But there's
-        // not many sensible things to do on OOM.  Failure seems fine (and is
-        // what the old stack allocation did).
-        let options = MmapOptions { stack: true };
-        let stack = match Mmap::anonymous_with_options(size, Protection::ReadCopy, options) {
-            Ok(map) => map,
-            Err(e) => panic!("mmap for stack of size {} failed: {}", size, e)
+        // Map in a stack. There's not many sensible things to do on OOM for
+        // most users, failure seems fine (and is what the old stack
+        // allocation did).
+        match StackBuilder::new(size).map() {
+            Ok(stack) => stack,
+            Err(StackError::Mprotect(e)) =>
+                panic!("Could not memory-protect guard page of stack of size {}: {}", size, e),
+            Err(e) => panic!("mmap for stack of size {} failed: {}", size, e),
+        }
+    }
+
+    /// Allocate a new stack of `size`, which has to be larger than a page
+    ///
+    /// Unlike `Stack::new` this returns an error instead of panicking if the
+    /// stack can't be allocated, so a caller can shed load on running out of
+    /// memory or mappings.
+    pub fn try_new(size: usize) -> Result<Stack, StackError> {
+        StackBuilder::new(size).build()
+    }
+
+    /// Create a 0-length stack which starts (and ends) at 0.
+    ///
+    /// # Safety
+    ///
+    /// The stack must not be used to run a context on.
+    #[allow(dead_code)]
+    pub unsafe fn dummy_stack() -> Stack {
+        Stack {
+            registration: None,
+            buf: None,
+            min_size: 0,
+            low_guard: 0,
+            high_guard: 0,
+            painted: false,
+            canary_words: 0,
+        }
+    }
+
+    /// Name the stack in the report of the overflow handler, like
+    /// `StackBuilder::name`, e.g. after a pool handed it out again
+    pub fn set_name(&mut self, name: Option<String>) {
+        match self.registration {
+            Some(ref registration) => registration.set_name(name),
+            None => self.register(name),
+        }
+    }
+
+    // Note the guard pages at the low end for the overflow handler, if it
+    // is installed, and the name for the report of a corrupted canary
+    fn register(&mut self, name: Option<String>) {
+        let canary = self.canary_words > 0;
+        if self.low_guard > 0 || canary {
+            let stack = (self.guard() as usize, self.end() as usize);
+            self.registration = Registration::new(self.start() as usize, self.low_guard, stack, name, canary);
+        }
+    }
+
+    /// Point to the high end of the guard pages at the low end of the stack
+    #[allow(dead_code)]
+    pub fn guard(&self) -> *const usize {
+        (self.start() as usize + self.low_guard) as *const usize
+    }
+
+    /// Release the physical memory behind the stack, the pages are zeroed
+    /// and faulted in again once used. The guard pages and the canary stay
+    /// in place.
+    ///
+    /// Only call this on a stack no context is running on.
+    pub fn decommit(&mut self) -> io::Result<()> {
+        // The canary stays
+        let canary = self.canary_words * mem::size_of::<usize>();
+        let lo = round_up(self.guard() as usize + canary, page_size());
+        let hi = self.end() as usize;
+        if hi <= lo {
+            return Ok(());
+        }
+        unsafe { decommit(lo as *mut u8, hi - lo) }
+    }
+
+    /// Fill the unused part of the stack with a canary, so
+    /// `Stack::high_water_mark` can tell how much of it gets used from now
+    /// on. If the stack has been painted before, only the part which got
+    /// used since is painted again.
+    ///
+    /// Only call this on a stack no context is running on.
+    pub fn paint(&mut self) {
+        let lo = self.guard() as usize;
+        let hi = self.end() as usize;
+        let from = match self.high_water_mark() {
+            Some(used) => hi - used,
+            None => lo,
         };
 
-        // Change the last page to be inaccessible. This is to provide safety;
-        // when an FFI function overflows it will (hopefully) hit this guard
-        // page. It isn't guaranteed, but that's why FFI is unsafe. buf.data is
-        // guaranteed to be aligned properly.
-        if !protect_last_page(&stack) {
-            panic!("Could not memory-protect guard page. stack={:?}",
-                  stack.ptr());
+        let mut word = from as *mut usize;
+        while (word as usize) < hi {
+            unsafe {
+                ptr::write_volatile(word, CANARY);
+                word = word.add(1);
+            }
+        }
+        self.painted = true;
+    }
+
+    /// The most bytes of the stack used since it has been painted, `None` if
+    /// it never has, see `StackBuilder::paint`
+    ///
+    /// This scans the stack for the deepest word which doesn't hold the
+    /// canary anymore. It may be a word short, if the deepest one written
+    /// happens to equal the canary. Pages released by `Stack::decommit` count
+    /// as unused until they are touched again, and then as used as a whole.
+    pub fn high_water_mark(&self) -> Option<usize> {
+        if !self.painted {
+            return None;
+        }
+
+        let lo = self.guard() as usize;
+        let hi = self.end() as usize;
+        let page = page_size();
+        // Where we can't tell, all pages are resident
+        let resident = unsafe { residency(lo as *mut u8, hi - lo) }.ok();
+
+        let mut addr = lo;
+        while addr < hi {
+            let untouched = resident.as_ref().is_some_and(|r| !r[(addr - lo) / page]);
+            if untouched {
+                addr = (addr / page + 1) * page;
+            } else if unsafe { ptr::read_volatile(addr as *const usize) } != CANARY {
+                return Some(hi - addr);
+            } else {
+                addr += mem::size_of::<usize>();
+            }
+        }
+        Some(0)
+    }
+
+    /// The bytes of the stack backed by physical memory right now, the guard
+    /// pages aside. Unlike `Stack::high_water_mark`, this works on any stack
+    /// and only takes a single `mincore` call, but it is only as precise as a
+    /// page, and pages stay resident once touched until the stack is
+    /// decommitted.
+    pub fn resident_bytes(&self) -> io::Result<usize> {
+        let lo = self.guard() as usize;
+        let hi = self.end() as usize;
+        if hi <= lo {
+            return Ok(0);
+        }
+        let resident = unsafe { residency(lo as *mut u8, hi - lo)? };
+        Ok(resident.iter().filter(|&&r| r).count() * page_size())
+    }
+
+    // The bytes mapped for the stack, guard pages included
+    fn mapped_len(&self) -> usize {
+        self.buf.as_ref().map(|buf| buf.len()).unwrap_or(0)
+    }
+
+    /// Point to the low end of the allocated stack
+    pub fn start(&self) -> *const usize {
+        self.buf.as_ref()
+            .map(|m| m.ptr() as *const usize)
+            .unwrap_or(ptr::null())
+    }
+
+    /// Point one usize beyond the high end of the allocated stack, below the
+    /// high guard page if there is one
+    pub fn end(&self) -> *const usize {
+        self.buf
+            .as_ref()
+            .map(|buf| unsafe {
+                buf.ptr().add(buf.len() - self.high_guard) as *const usize
+            })
+            .unwrap_or(ptr::null())
+    }
+}
+
+/// Memory a context can run on
+///
+/// Contexts only look at the bounds, the memory has to stay around for as
+/// long as a context runs on it.
+pub trait StackSource {
+    /// Point to the low end of the usable stack
+    fn start(&self) -> *const usize;
+
+    /// Point one usize beyond the high end of the stack
+    fn end(&self) -> *const usize;
+
+    /// The words at the low end of the stack which hold a canary, if any,
+    /// and how many there are. A context running on the stack checks them
+    /// whenever it gets switched away from.
+    fn canary(&self) -> Option<(*const usize, usize)> {
+        None
+    }
+
+    /// Whether contexts take turns running on the stack, see `SharedStack`
+    fn is_shared(&self) -> bool {
+        false
+    }
+}
+
+impl StackSource for Stack {
+    fn start(&self) -> *const usize {
+        Stack::start(self)
+    }
+
+    fn end(&self) -> *const usize {
+        Stack::end(self)
+    }
+
+    fn canary(&self) -> Option<(*const usize, usize)> {
+        if self.canary_words == 0 {
+            None
+        } else {
+            Some((self.guard(), self.canary_words))
+        }
+    }
+}
+
+/// A stack on the heap. There is no guard page below it, so overflowing it
+/// silently corrupts whatever lives there.
+pub struct HeapStack {
+    buf: Box<[u8]>,
+}
+
+impl fmt::Debug for HeapStack {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "HeapStack {{ buf: {:#x}, len: {} }}",
+               self.buf.as_ptr() as libc::uintptr_t, self.buf.len())
+    }
+}
+
+impl HeapStack {
+    /// Allocate a new stack of `size` on the heap
+    pub fn new(size: usize) -> HeapStack {
+        HeapStack {
+            buf: vec![0; size].into_boxed_slice(),
+        }
+    }
+}
+
+impl StackSource for HeapStack {
+    fn start(&self) -> *const usize {
+        self.buf.as_ptr() as *const usize
+    }
+
+    fn end(&self) -> *const usize {
+        unsafe { self.buf.as_ptr().add(self.buf.len()) as *const usize }
+    }
+}
+
+/// Memory supplied by the caller, e.g. a `static mut` array
+impl StackSource for &'static mut [u8] {
+    fn start(&self) -> *const usize {
+        self.as_ptr() as *const usize
+    }
+
+    fn end(&self) -> *const usize {
+        unsafe { self.as_ptr().add(self.len()) as *const usize }
+    }
+}
+
+/// A stack many contexts take turns running on
+///
+/// A context made by `Context::new` or `Context::with_closure` on a shared
+/// stack only occupies it while it runs. Whenever it is switched away from,
+/// the live part of the stack, from its stack pointer up to the top, is copied
+/// to the heap, and copied back in once it is resumed. A lot of mostly idle
+/// contexts then take as much memory as their stacks are deep rather than a
+/// stack each, at the price of two copies on every switch.
+///
+/// With the `ucontext` feature on targets where the layout of the saved
+/// machine state isn't known, see `Context::stack_pointer`, the stack
+/// pointer of a suspended context isn't known either. The whole stack is
+/// copied out and back in then, however little of it is in use, so keep
+/// shared stacks small there.
+///
+/// A context suspended on a shared stack must not live on that stack itself,
+/// box it instead, and `Context::save` doesn't work on it. The stack has to
+/// stay around for as long as any of its contexts may be resumed.
+#[derive(Debug)]
+pub struct SharedStack<S = Stack> {
+    stack: S,
+}
+
+impl<S: StackSource> SharedStack<S> {
+    /// Share `stack` between contexts
+    pub fn new(stack: S) -> SharedStack<S> {
+        SharedStack {
+            stack,
+        }
+    }
+
+    /// Take the stack back, once none of its contexts will be resumed
+    pub fn into_inner(self) -> S {
+        self.stack
+    }
+}
+
+impl<S: StackSource> StackSource for SharedStack<S> {
+    fn start(&self) -> *const usize {
+        self.stack.start()
+    }
+
+    fn end(&self) -> *const usize {
+        self.stack.end()
+    }
+
+    fn canary(&self) -> Option<(*const usize, usize)> {
+        self.stack.canary()
+    }
+
+    fn is_shared(&self) -> bool {
+        true
+    }
+}
+
+/// Hands out stacks for contexts to run on
+pub trait StackAllocator {
+    type Stack: StackSource;
+
+    /// Allocate a stack of at least `size` bytes
+    fn allocate(&mut self, size: usize) -> Result<Self::Stack, StackError>;
+
+    /// Take back a stack no context runs on anymore, it is dropped by default
+    fn deallocate(&mut self, stack: Self::Stack) {
+        drop(stack);
+    }
+}
+
+/// Allocates a `Stack` with `mmap` and a guard page, see `Stack::try_new`
+#[derive(Debug, Copy, Clone, Default)]
+pub struct MmapStackAllocator;
+
+impl StackAllocator for MmapStackAllocator {
+    type Stack = Stack;
+
+    fn allocate(&mut self, size: usize) -> Result<Stack, StackError> {
+        Stack::try_new(size)
+    }
+}
+
+/// Allocates a `HeapStack`
+#[derive(Debug, Copy, Clone, Default)]
+pub struct HeapStackAllocator;
+
+impl StackAllocator for HeapStackAllocator {
+    type Stack = HeapStack;
+
+    fn allocate(&mut self, size: usize) -> Result<HeapStack, StackError> {
+        if size == 0 {
+            return Err(StackError::InvalidSize(size));
+        }
+        Ok(HeapStack::new(size))
+    }
+}
+
+// The memory behind a `Stack`
+enum StackMemory {
+    Mapping(Mapping),
+    Slab(SlabSlot),
+}
+
+impl StackMemory {
+    fn ptr(&self) -> *mut u8 {
+        match *self {
+            StackMemory::Mapping(ref m) => m.ptr,
+            StackMemory::Slab(ref s) => s.ptr,
+        }
+    }
+
+    fn len(&self) -> usize {
+        match *self {
+            StackMemory::Mapping(ref m) => m.len,
+            StackMemory::Slab(ref s) => s.slab.stride,
+        }
+    }
+}
+
+/// Many stacks of the same size carved out of one mapping
+///
+/// Each stack has a guard page at its low end, which are all set up when the
+/// slab is created, so taking a stack out of it doesn't take any system call.
+/// On Linux the guard pages are installed with `MADV_GUARD_INSTALL` where the
+/// kernel supports it, which doesn't split up the mapping, so the whole slab
+/// only counts once against `vm.max_map_count`.
+///
+/// The stacks are handed out as `Stack` values, which return to the slab when
+/// dropped. The mapping is released once the slab and all its stacks are
+/// gone. It is mapped with `MAP_NORESERVE`, the memory is only committed once
+/// a stack is actually used.
+#[derive(Clone)]
+pub struct StackSlab {
+    inner: Arc<SlabInner>,
+}
+
+struct SlabInner {
+    map: Mapping,
+    // The size of each stack, guard page included
+    stride: usize,
+    free: Mutex<Vec<usize>>,
+}
+
+impl fmt::Debug for StackSlab {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "StackSlab {{ buf: {:#x}, stack_size: {}, capacity: {}, available: {} }}",
+               self.inner.map.ptr as libc::uintptr_t, self.stack_size(),
+               self.capacity(), self.available())
+    }
+}
+
+impl StackSlab {
+    /// Reserve room for `count` stacks of `stack_size` bytes each, guard page
+    /// included like with `Stack::new`. The size is rounded up to whole
+    /// pages.
+    pub fn new(stack_size: usize, count: usize) -> Result<StackSlab, StackError> {
+        let page = page_size();
+        let stride = round_up(stack_size, page);
+        if stride <= page {
+            return Err(StackError::InvalidSize(stack_size));
+        }
+        let len = match stride.checked_mul(count) {
+            Some(len) if len > 0 => len,
+            _ => return Err(StackError::InvalidSize(stack_size)),
+        };
+
+        let map = Mapping::new(len, true, false, false).map_err(StackError::Mmap)?;
+        for idx in 0..count {
+            unsafe {
+                install_guard(map.ptr.add(idx * stride), page).map_err(StackError::Mprotect)?;
+            }
+        }
+
+        Ok(StackSlab {
+            inner: Arc::new(SlabInner {
+                map,
+                stride,
+                // Hand out the lowest stacks first
+                free: Mutex::new((0..count).rev().collect()),
+            }),
+        })
+    }
+
+    /// Take a stack out of the slab, `None` if all of them are in use
+    pub fn take_stack(&self) -> Option<Stack> {
+        let idx = self.inner.free.lock().unwrap().pop()?;
+        let mut stack = Stack {
+            registration: None,
+            buf: Some(StackMemory::Slab(SlabSlot {
+                ptr: unsafe { self.inner.map.ptr.add(idx * self.inner.stride) },
+                idx,
+                slab: self.inner.clone(),
+            })),
+            min_size: self.inner.stride,
+            low_guard: page_size(),
+            high_guard: 0,
+            painted: false,
+            canary_words: 0,
+        };
+        stack.register(None);
+        Some(stack)
+    }
+
+    /// The size of each stack, guard page included
+    pub fn stack_size(&self) -> usize {
+        self.inner.stride
+    }
+
+    /// The number of stacks in the slab
+    pub fn capacity(&self) -> usize {
+        self.inner.map.len / self.inner.stride
+    }
+
+    /// The number of stacks which can be taken right now
+    pub fn available(&self) -> usize {
+        self.inner.free.lock().unwrap().len()
+    }
+}
+
+// A stack of a slab, handed back when dropped
+struct SlabSlot {
+    ptr: *mut u8,
+    idx: usize,
+    slab: Arc<SlabInner>,
+}
+
+// Like the mapping, the slot is owned
+unsafe impl Send for SlabSlot {}
+
+impl Drop for SlabSlot {
+    fn drop(&mut self) {
+        if let Ok(mut free) = self.slab.free.lock() {
+            free.push(self.idx);
+        }
+    }
+}
+
+// An anonymous private mapping, unmapped on drop
+struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// The mapping is owned, nothing ties it to the thread which made it
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+#[cfg(any(all(target_os = "linux", not(target_arch = "mips")),
+          target_os = "freebsd",
+          target_os = "android"))]
+const MAP_STACK: libc::c_int = libc::MAP_STACK;
+#[cfg(not(any(all(target_os = "linux", not(target_arch = "mips")),
+              target_os = "freebsd",
+              target_os = "android")))]
+const MAP_STACK: libc::c_int = 0;
+
+#[cfg(target_os = "linux")]
+const MAP_POPULATE: libc::c_int = libc::MAP_POPULATE;
+#[cfg(all(unix, not(target_os = "linux")))]
+const MAP_POPULATE: libc::c_int = 0;
+
+#[cfg(all(target_os = "linux", any(target_arch = "mips", target_arch = "mips64")))]
+const MAP_HUGETLB: libc::c_int = 0x80000;
+#[cfg(all(target_os = "linux", not(any(target_arch = "mips", target_arch = "mips64"))))]
+const MAP_HUGETLB: libc::c_int = 0x40000;
+#[cfg(all(unix, not(target_os = "linux")))]
+const MAP_HUGETLB: libc::c_int = 0;
+
+#[cfg(unix)]
+impl Mapping {
+    fn new(len: usize, noreserve: bool, populate: bool, huge: bool) -> io::Result<Mapping> {
+        let mut flags = libc::MAP_PRIVATE | libc::MAP_ANON | MAP_STACK;
+        if noreserve { flags |= libc::MAP_NORESERVE }
+        if populate { flags |= MAP_POPULATE }
+        if huge { flags |= MAP_HUGETLB }
+
+        let ptr = unsafe {
+            libc::mmap(ptr::null_mut(), len as libc::size_t,
+                       libc::PROT_READ | libc::PROT_WRITE, flags, -1, 0)
+        };
+        if ptr == libc::MAP_FAILED {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(Mapping { ptr: ptr as *mut u8, len })
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.len as libc::size_t);
+        }
+    }
+}
+
+#[cfg(windows)]
+impl Mapping {
+    fn new(len: usize, _noreserve: bool, _populate: bool, _huge: bool) -> io::Result<Mapping> {
+        let ptr = unsafe {
+            libc::VirtualAlloc(ptr::null_mut(), len as libc::SIZE_T,
+                               libc::MEM_RESERVE | libc::MEM_COMMIT,
+                               libc::PAGE_READWRITE)
+        };
+        if ptr.is_null() {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(Mapping { ptr: ptr as *mut u8, len })
+        }
+    }
+}
+
+#[cfg(windows)]
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe {
+            libc::VirtualFree(self.ptr as libc::LPVOID, 0, libc::MEM_RELEASE);
+        }
+    }
+}
+
+// Make `len` bytes at `ptr` inaccessible. This may seem backwards: the start
+// of the segment is the last page? Yes! The stack grows from higher addresses
+// (the end of the allocated block) to lower addresses (the start of the
+// allocated block).
+#[cfg(unix)]
+unsafe fn protect(ptr: *mut u8, len: usize) -> io::Result<()> {
+    if len == 0 {
+        return Ok(());
+    }
+    if libc::mprotect(ptr as *mut libc::c_void, len as libc::size_t, libc::PROT_NONE) == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+unsafe fn protect(ptr: *mut u8, len: usize) -> io::Result<()> {
+    if len == 0 {
+        return Ok(());
+    }
+    let mut old_prot: libc::DWORD = 0;
+    if libc::VirtualProtect(ptr as libc::LPVOID, len as libc::SIZE_T,
+                            libc::PAGE_NOACCESS,
+                            &mut old_prot as libc::LPDWORD) == 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+// The pattern painted onto stacks, and their canary
+pub(crate) const CANARY: usize = usize::MAX / 0xff * 0xa5;
+
+// Whether each page of `len` bytes at `ptr` is resident
+#[cfg(unix)]
+unsafe fn residency(ptr: *mut u8, len: usize) -> io::Result<Vec<bool>> {
+    let mut vec = vec![0u8; len.div_ceil(page_size())];
+    if libc::funcs::bsd44::mincore(ptr as *mut libc::c_void, len as libc::size_t,
+                                   vec.as_mut_ptr()) == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(vec.into_iter().map(|v| v & 1 != 0).collect())
+}
+
+#[cfg(windows)]
+unsafe fn residency(_: *mut u8, _: usize) -> io::Result<Vec<bool>> {
+    Err(io::Error::new(io::ErrorKind::Other, "mincore is not supported on Windows"))
+}
+
+#[cfg(unix)]
+unsafe fn decommit(ptr: *mut u8, len: usize) -> io::Result<()> {
+    if libc::funcs::bsd44::madvise(ptr as *mut libc::c_void, len as libc::size_t,
+                                   libc::MADV_DONTNEED) == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+unsafe fn decommit(ptr: *mut u8, len: usize) -> io::Result<()> {
+    // MEM_RESET keeps the pages committed, only their content is discarded,
+    // so zero them the way MADV_DONTNEED does by decommitting them
+    if libc::VirtualFree(ptr as libc::LPVOID, len as libc::SIZE_T, libc::MEM_DECOMMIT) == 0 ||
+       libc::VirtualAlloc(ptr as libc::LPVOID, len as libc::SIZE_T,
+                          libc::MEM_COMMIT, libc::PAGE_READWRITE).is_null() {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+// Make `len` bytes at `ptr` a guard region, without splitting up the mapping
+// where the kernel knows how to
+#[cfg(target_os = "linux")]
+unsafe fn install_guard(ptr: *mut u8, len: usize) -> io::Result<()> {
+    const MADV_GUARD_INSTALL: libc::c_int = 102;
+    if libc::funcs::bsd44::madvise(ptr as *mut libc::c_void, len as libc::size_t,
+                                   MADV_GUARD_INSTALL) == 0 {
+        return Ok(());
+    }
+    protect(ptr, len)
+}
+
+#[cfg(not(target_os = "linux"))]
+unsafe fn install_guard(ptr: *mut u8, len: usize) -> io::Result<()> {
+    protect(ptr, len)
+}
+
+#[cfg(target_os = "linux")]
+unsafe fn advise_huge_pages(ptr: *mut u8, len: usize) {
+    const MADV_HUGEPAGE: libc::c_int = 14;
+    libc::funcs::bsd44::madvise(ptr as *mut libc::c_void, len as libc::size_t, MADV_HUGEPAGE);
+}
+
+#[cfg(not(target_os = "linux"))]
+unsafe fn advise_huge_pages(_: *mut u8, _: usize) {
+}
+
+// Touch every page, where the mapping can't be populated right away
+fn prefault(ptr: *mut u8, len: usize) {
+    let page = page_size();
+    let mut offset = 0;
+    while offset < len {
+        unsafe { ptr::write_volatile(ptr.add(offset), 0) };
+        offset += page;
+    }
+}
+
+fn round_up(size: usize, unit: usize) -> usize {
+    size.div_ceil(unit) * unit
+}
+
+/// How many stacks a `StackPool` keeps around
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct StackPoolConfig {
+    /// The most bytes the cached stacks may add up to
+    pub max_bytes: usize,
+    /// The most stacks to cache
+    pub max_stacks: usize,
+    /// Which stacks to release the memory of when they are given back
+    pub decommit: DecommitPolicy,
+    /// Measure how much of the stacks given back got used, see
+    /// `StackPool::usage`. The stacks mapped by the pool are painted then,
+    /// see `StackBuilder::paint`.
+    pub measure: bool,
+}
+
+impl Default for StackPoolConfig {
+    /// 20M of cache per scheduler, ten stacks at the default size, whose
+    /// memory is kept
+    fn default() -> StackPoolConfig {
+        StackPoolConfig {
+            max_bytes: 20 * 1024 * 1024,
+            max_stacks: 10,
+            decommit: DecommitPolicy::Never,
+            measure: false,
+        }
+    }
+}
+
+/// When a `StackPool` calls `Stack::decommit` on the stacks given back
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DecommitPolicy {
+    /// Keep the memory, the next context taking the stack runs on warm pages
+    Never,
+    /// Release the memory of every stack
+    Always,
+    /// Release the memory of stacks of at least this many bytes, where a
+    /// deep recursion may have left a lot behind
+    AtLeast(usize),
+}
+
+/// A cache of stacks, so spawning a context doesn't have to map a new one
+///
+/// Stacks are kept in buckets by the power of two their size rounds down to,
+/// and within a bucket by their exact size. Giving back a stack and taking
+/// one out only look at the different sizes of one bucket, which is just
+/// one or two for most programs, so neither depends on how many stacks are
+/// cached. Once the cached stacks exceed the budget of the
+/// `StackPoolConfig`, the ones given back longest ago are dropped first, in
+/// O(1) each.
+#[derive(Debug, Default)]
+pub struct StackPool {
+    config: StackPoolConfig,
+    // The cached stacks, and the slots of the ones taken out again in `free`
+    entries: Vec<Entry>,
+    free: Vec<usize>,
+    // All the cached stacks, from the least to the most recently given back
+    lru: List,
+    // Bucket n holds the stacks of 2^n up to 2^(n+1) bytes, by exact size
+    buckets: Vec<Vec<SizeList>>,
+    // Bit n is set if bucket n holds any stack
+    occupied: u64,
+    clock: u64,
+    cached_stacks: usize,
+    cached_bytes: usize,
+    // Where new stacks are drawn from before mapping them one by one
+    slabs: Vec<StackSlab>,
+    usage: UsageCounters,
+}
+
+// The end of a list
+const NIL: usize = usize::MAX;
+
+// A cached stack, stamped with when it was given back and linked into both
+// the list of all the stacks and the one of its size
+#[derive(Debug)]
+struct Entry {
+    stack: Option<Stack>,
+    stamp: u64,
+    lru: Links,
+    same_size: Links,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Links {
+    prev: usize,
+    next: usize,
+}
+
+// A list through `entries`, from the least to the most recently given back
+#[derive(Debug, Copy, Clone)]
+struct List {
+    head: usize,
+    tail: usize,
+}
+
+impl Default for List {
+    fn default() -> List {
+        List { head: NIL, tail: NIL }
+    }
+}
+
+// The stacks of one exact size within a bucket
+#[derive(Debug)]
+struct SizeList {
+    size: usize,
+    list: List,
+}
+
+fn lru_links(entry: &mut Entry) -> &mut Links {
+    &mut entry.lru
+}
+
+fn same_size_links(entry: &mut Entry) -> &mut Links {
+    &mut entry.same_size
+}
+
+fn push_back(entries: &mut [Entry], list: &mut List, idx: usize,
+             links: fn(&mut Entry) -> &mut Links) {
+    *links(&mut entries[idx]) = Links { prev: list.tail, next: NIL };
+    match list.tail {
+        NIL => list.head = idx,
+        tail => links(&mut entries[tail]).next = idx,
+    }
+    list.tail = idx;
+}
+
+fn unlink(entries: &mut [Entry], list: &mut List, idx: usize,
+          links: fn(&mut Entry) -> &mut Links) {
+    let Links { prev, next } = *links(&mut entries[idx]);
+    match prev {
+        NIL => list.head = next,
+        prev => links(&mut entries[prev]).next = next,
+    }
+    match next {
+        NIL => list.tail = prev,
+        next => links(&mut entries[next]).prev = prev,
+    }
+}
+
+impl StackPool {
+    pub fn new() -> StackPool {
+        StackPool::with_config(StackPoolConfig::default())
+    }
+
+    pub fn with_config(config: StackPoolConfig) -> StackPool {
+        StackPool {
+            config,
+            entries: vec![],
+            free: vec![],
+            lru: List::default(),
+            buckets: vec![],
+            occupied: 0,
+            clock: 0,
+            cached_stacks: 0,
+            cached_bytes: 0,
+            slabs: vec![],
+            usage: UsageCounters::default(),
+        }
+    }
+
+    pub fn config(&self) -> &StackPoolConfig {
+        &self.config
+    }
+
+    /// Change the budget, dropping cached stacks which exceed it right away
+    pub fn set_config(&mut self, config: StackPoolConfig) {
+        self.config = config;
+        self.enforce_budget();
+    }
+
+    /// The number of stacks cached
+    pub fn len(&self) -> usize {
+        self.cached_stacks
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cached_stacks == 0
+    }
+
+    /// The bytes of all the stacks cached
+    pub fn cached_bytes(&self) -> usize {
+        self.cached_bytes
+    }
+
+    /// Draw new stacks from `slab` as long as it has any left and they are
+    /// large enough
+    pub fn add_slab(&mut self, slab: StackSlab) {
+        self.slabs.push(slab);
+    }
+
+    pub fn take_stack(&mut self, min_size: usize) -> Stack {
+        match self.take_cached(min_size) {
+            Some(stack) => stack,
+            None => prepare(&self.config, Stack::new(min_size))
         }
+    }
 
-        Stack {
-            buf: Some(stack),
-            min_size: size,
+    /// Like `take_stack`, but returns an error instead of panicking if a new
+    /// stack has to be allocated and that fails, see `Stack::try_new`
+    pub fn try_take_stack(&mut self, min_size: usize) -> Result<Stack, StackError> {
+        match self.take_cached(min_size) {
+            Some(stack) => Ok(stack),
+            None => Stack::try_new(min_size).map(|stack| prepare(&self.config, stack))
         }
     }
 
-    /// Create a 0-length stack which starts (and ends) at 0.
-    #[allow(dead_code)]
-    pub unsafe fn dummy_stack() -> Stack {
-        Stack {
-            buf: None,
-            min_size: 0,
+    /// How much of the stacks given back so far got used, if the pool
+    /// measures them, see `StackPoolConfig::measure`
+    pub fn usage(&self) -> StackUsage {
+        self.usage.get()
+    }
+
+    fn take_cached(&mut self, min_size: usize) -> Option<Stack> {
+        // Within the bucket min_size falls into only the sizes from min_size
+        // up fit, all of the buckets above hold large enough stacks. Either
+        // way it's the most recently given back one of them.
+        let class = size_class(min_size);
+        let above = self.occupied.checked_shr(class as u32 + 1).unwrap_or(0);
+        let cached = self.most_recent(class, min_size).or_else(|| match above {
+            0 => None,
+            _ => self.most_recent(class + 1 + above.trailing_zeros() as usize, 0),
+        });
+        match cached {
+            Some(idx) => Some(self.remove(idx)),
+            None => self.slabs.iter()
+                .filter(|slab| min_size <= slab.stack_size())
+                .filter_map(|slab| slab.take_stack())
+                .next()
+                .map(|stack| prepare(&self.config, stack)),
         }
     }
 
-    #[allow(dead_code)]
-    pub fn guard(&self) -> *const usize {
-        (self.start() as usize + page_size()) as *const usize
+    // The most recently given back stack of at least min_size bytes in the
+    // bucket, which the newest of each size is a candidate for
+    fn most_recent(&self, class: usize, min_size: usize) -> Option<usize> {
+        self.buckets.get(class)?.iter()
+            .filter(|s| min_size <= s.size)
+            .map(|s| s.list.tail)
+            .max_by_key(|&idx| self.entries[idx].stamp)
     }
 
-    /// Point to the low end of the allocated stack
-    pub fn start(&self) -> *const usize {
-        self.buf.as_ref()
-            .map(|m| m.ptr() as *const usize)
-            .unwrap_or(ptr::null())
+    pub fn give_stack(&mut self, mut stack: Stack) {
+        recycle(&self.config, &self.usage, &mut stack);
+        self.cache(stack);
     }
 
-    /// Point one usize beyond the high end of the allocated stack
-    pub fn end(&self) -> *const usize {
-        self.buf
-            .as_ref()
-            .map(|buf| unsafe {
-                buf.ptr().offset(buf.len() as isize) as *const usize
-            })
-            .unwrap_or(ptr::null())
+    fn cache(&mut self, stack: Stack) {
+        let len = stack.mapped_len();
+        if len > self.config.max_bytes || self.config.max_stacks == 0 {
+            return;
+        }
+
+        let size = stack.min_size;
+        self.clock += 1;
+        let entry = Entry {
+            stack: Some(stack),
+            stamp: self.clock,
+            lru: Links { prev: NIL, next: NIL },
+            same_size: Links { prev: NIL, next: NIL },
+        };
+        let idx = match self.free.pop() {
+            Some(idx) => {
+                self.entries[idx] = entry;
+                idx
+            }
+            None => {
+                self.entries.push(entry);
+                self.entries.len() - 1
+            }
+        };
+        push_back(&mut self.entries, &mut self.lru, idx, lru_links);
+
+        let class = size_class(size);
+        if self.buckets.len() <= class {
+            self.buckets.resize_with(class + 1, Vec::new);
+        }
+        let bucket = &mut self.buckets[class];
+        let pos = match bucket.iter().position(|s| s.size == size) {
+            Some(pos) => pos,
+            None => {
+                bucket.push(SizeList { size, list: List::default() });
+                bucket.len() - 1
+            }
+        };
+        push_back(&mut self.entries, &mut bucket[pos].list, idx, same_size_links);
+        self.occupied |= 1 << class;
+
+        self.cached_stacks += 1;
+        self.cached_bytes += len;
+        self.enforce_budget();
+    }
+
+    // Take the stack at idx out of both of its lists
+    fn remove(&mut self, idx: usize) -> Stack {
+        let stack = self.entries[idx].stack.take().expect("cached stack");
+        unlink(&mut self.entries, &mut self.lru, idx, lru_links);
+
+        let class = size_class(stack.min_size);
+        let bucket = &mut self.buckets[class];
+        let pos = bucket.iter()
+            .position(|s| s.size == stack.min_size)
+            .expect("size list of a cached stack");
+        unlink(&mut self.entries, &mut bucket[pos].list, idx, same_size_links);
+        if bucket[pos].list.head == NIL {
+            bucket.swap_remove(pos);
+            if bucket.is_empty() {
+                self.occupied &= !(1 << class);
+            }
+        }
+
+        self.free.push(idx);
+        self.cached_stacks -= 1;
+        self.cached_bytes -= stack.mapped_len();
+        stack
+    }
+
+    /// Drop the least recently given back stacks until the cached ones add
+    /// up to at most `bytes`, e.g. to return memory after a load spike
+    pub fn shrink_to(&mut self, bytes: usize) {
+        while self.cached_bytes > bytes {
+            self.evict_oldest();
+        }
+    }
+
+    fn enforce_budget(&mut self) {
+        let max_bytes = self.config.max_bytes;
+        self.shrink_to(max_bytes);
+        while self.cached_stacks > self.config.max_stacks {
+            self.evict_oldest();
+        }
+    }
+
+    fn evict_oldest(&mut self) {
+        if self.lru.head != NIL {
+            let oldest = self.lru.head;
+            self.remove(oldest);
+        }
     }
 }
 
-#[cfg(unix)]
-fn protect_last_page(stack: &Mmap) -> bool {
-    unsafe {
-        // This may seem backwards: the start of the segment is the last page?
-        // Yes! The stack grows from higher addresses (the end of the allocated
-        // block) to lower addresses (the start of the allocated block).
-        let last_page = stack.ptr() as *mut libc::c_void;
-        libc::mprotect(last_page, page_size() as libc::size_t,
-                       libc::PROT_NONE) != -1
+/// How often a pool had a stack at hand
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct StackPoolStats {
+    /// Stacks taken out of the cache
+    pub hits: usize,
+    /// Stacks which had to be allocated
+    pub misses: usize,
+}
+
+// The stacks a thread keeps for itself before going to the shared shards
+const MAGAZINE_SIZE: usize = 8;
+
+/// A `StackPool` shared by many threads
+///
+/// Stacks given back on one thread can be taken on another one. Every thread
+/// keeps a small magazine of the stacks it gave back last, which it takes from
+/// without any synchronization. Beyond that, stacks go to one of a few
+/// sharded `StackPool`s, one per CPU, each with its share of the budget of
+/// the `StackPoolConfig`. The magazines come on top of the budget, a thread
+/// hands its stacks over to the shards when it exits. A magazine is small
+/// enough to be searched stack by stack, so taking a stack is as cheap as
+/// with a `StackPool`.
+#[derive(Clone)]
+pub struct SharedStackPool {
+    inner: Arc<SharedInner>,
+}
+
+struct SharedInner {
+    config: StackPoolConfig,
+    shards: Vec<Mutex<StackPool>>,
+    usage: UsageCounters,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+impl fmt::Debug for SharedStackPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SharedStackPool {{ shards: {}, stats: {:?} }}",
+               self.inner.shards.len(), self.stats())
     }
 }
 
-#[cfg(windows)]
-fn protect_last_page(stack: &Mmap) -> bool {
-    unsafe {
-        // see above
-        let last_page = stack.ptr() as *mut libc::c_void;
-        let mut old_prot: libc::DWORD = 0;
-        libc::VirtualProtect(last_page, page_size() as libc::SIZE_T,
-                             libc::PAGE_NOACCESS,
-                             &mut old_prot as libc::LPDWORD) != 0
+impl Default for SharedStackPool {
+    fn default() -> SharedStackPool {
+        SharedStackPool::new()
     }
 }
 
-#[derive(Debug)]
-pub struct StackPool {
-    // Ideally this would be some data structure that preserved ordering on
-    // Stack.min_size.
+// A thread's stacks of one `SharedStackPool`
+struct Magazine {
+    pool: Weak<SharedInner>,
     stacks: Vec<Stack>,
 }
 
-impl StackPool {
-    pub fn new() -> StackPool {
-        StackPool {
-            stacks: vec![],
+impl Drop for Magazine {
+    fn drop(&mut self) {
+        if let Some(pool) = self.pool.upgrade() {
+            let mut shard = pool.shard().lock().unwrap();
+            for stack in self.stacks.drain(..) {
+                shard.cache(stack);
+            }
         }
     }
+}
 
-    pub fn take_stack(&mut self, min_size: usize) -> Stack {
-        // Ideally this would be a binary search
-        match self.stacks.iter().position(|s| min_size <= s.min_size) {
-            Some(idx) => self.stacks.swap_remove(idx),
-            None => Stack::new(min_size)
+thread_local!(static MAGAZINES: RefCell<Vec<Magazine>> = const { RefCell::new(Vec::new()) });
+
+impl SharedStackPool {
+    pub fn new() -> SharedStackPool {
+        SharedStackPool::with_config(StackPoolConfig::default())
+    }
+
+    pub fn with_config(config: StackPoolConfig) -> SharedStackPool {
+        let n = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+        let shard_config = StackPoolConfig {
+            max_bytes: config.max_bytes.div_ceil(n),
+            max_stacks: config.max_stacks.div_ceil(n),
+            ..config
+        };
+        SharedStackPool {
+            inner: Arc::new(SharedInner {
+                config,
+                shards: (0..n).map(|_| Mutex::new(StackPool::with_config(shard_config))).collect(),
+                usage: UsageCounters::default(),
+                hits: AtomicUsize::new(0),
+                misses: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// Draw new stacks from `slab` as long as it has any left and they are
+    /// large enough
+    pub fn add_slab(&self, slab: StackSlab) {
+        self.inner.shard().lock().unwrap().add_slab(slab);
+    }
+
+    pub fn take_stack(&self, min_size: usize) -> Stack {
+        match self.take_cached(min_size) {
+            Some(stack) => stack,
+            None => prepare(&self.inner.config, Stack::new(min_size))
+        }
+    }
+
+    /// Like `take_stack`, but returns an error instead of panicking if a new
+    /// stack has to be allocated and that fails, see `Stack::try_new`
+    pub fn try_take_stack(&self, min_size: usize) -> Result<Stack, StackError> {
+        match self.take_cached(min_size) {
+            Some(stack) => Ok(stack),
+            None => Stack::try_new(min_size).map(|stack| prepare(&self.inner.config, stack))
+        }
+    }
+
+    fn take_cached(&self, min_size: usize) -> Option<Stack> {
+        // A magazine holds no more than MAGAZINE_SIZE stacks, so searching
+        // it costs no more than a lookup in the shard would
+        let stack = self.with_magazine(|m| {
+            m.stacks.iter()
+                .rposition(|s| min_size <= s.min_size)
+                .map(|idx| m.stacks.swap_remove(idx))
+        }).or_else(|| self.inner.take_from_shards(min_size));
+
+        let counter = if stack.is_some() { &self.inner.hits } else { &self.inner.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+        stack
+    }
+
+    pub fn give_stack(&self, mut stack: Stack) {
+        recycle(&self.inner.config, &self.inner.usage, &mut stack);
+        let spilled = self.with_magazine(|m| {
+            m.stacks.push(stack);
+            if m.stacks.len() > MAGAZINE_SIZE {
+                // Keep the stacks given back last, they are the warmest ones
+                Some(m.stacks.drain(..MAGAZINE_SIZE / 2).collect::<Vec<_>>())
+            } else {
+                None
+            }
+        });
+        if let Some(stacks) = spilled {
+            let mut shard = self.inner.shard().lock().unwrap();
+            for stack in stacks {
+                shard.cache(stack);
+            }
+        }
+    }
+
+    /// How often `take_stack` found a cached stack so far
+    pub fn stats(&self) -> StackPoolStats {
+        StackPoolStats {
+            hits: self.inner.hits.load(Ordering::Relaxed),
+            misses: self.inner.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    /// How much of the stacks given back so far got used, if the pool
+    /// measures them, see `StackPoolConfig::measure`
+    pub fn usage(&self) -> StackUsage {
+        self.inner.usage.get()
+    }
+
+    // Run `f` on the magazine of the current thread. Without one, as while
+    // the thread is exiting, it goes straight to the shards instead.
+    fn with_magazine<F, R>(&self, f: F) -> R
+        where F: FnOnce(&mut Magazine) -> R
+    {
+        let mut f = Some(f);
+        let r = MAGAZINES.try_with(|magazines| {
+            let mut magazines = magazines.borrow_mut();
+            // Forget about the magazines of pools which are gone
+            magazines.retain(|m| m.pool.strong_count() > 0);
+
+            let idx = match magazines.iter().position(|m| ptr::eq(m.pool.as_ptr(), &*self.inner)) {
+                Some(idx) => idx,
+                None => {
+                    magazines.push(Magazine {
+                        pool: Arc::downgrade(&self.inner),
+                        stacks: Vec::with_capacity(MAGAZINE_SIZE + 1),
+                    });
+                    magazines.len() - 1
+                }
+            };
+            (f.take().unwrap())(&mut magazines[idx])
+        });
+        match r {
+            Ok(r) => r,
+            Err(_) => {
+                let mut m = Magazine { pool: Arc::downgrade(&self.inner), stacks: vec![] };
+                // Dropping the magazine hands over whatever is left in it
+                (f.take().unwrap())(&mut m)
+            }
+        }
+    }
+}
+
+impl SharedInner {
+    // The shard of the current thread
+    fn shard(&self) -> &Mutex<StackPool> {
+        thread_local!(static SHARD: usize = {
+            static NEXT: AtomicUsize = AtomicUsize::new(0);
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        });
+        let idx = SHARD.try_with(|s| *s).unwrap_or(0);
+        &self.shards[idx % self.shards.len()]
+    }
+
+    // Look into our own shard first, then into the others unless they are
+    // busy
+    fn take_from_shards(&self, min_size: usize) -> Option<Stack> {
+        let own = self.shard();
+        if let Some(stack) = own.lock().unwrap().take_cached(min_size) {
+            return Some(stack);
+        }
+        self.shards.iter()
+            .filter(|shard| !ptr::eq(*shard, own))
+            .filter_map(|shard| shard.try_lock().ok())
+            .filter_map(|mut shard| shard.take_cached(min_size))
+            .next()
+    }
+}
+
+impl StackAllocator for SharedStackPool {
+    type Stack = Stack;
+
+    fn allocate(&mut self, size: usize) -> Result<Stack, StackError> {
+        self.try_take_stack(size)
+    }
+
+    fn deallocate(&mut self, stack: Stack) {
+        self.give_stack(stack)
+    }
+}
+
+/// How much of the stacks given back to a pool got used, see
+/// `Stack::high_water_mark` and `Stack::resident_bytes`
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct StackUsage {
+    /// The stacks measured
+    pub stacks: usize,
+    /// The stacks measured which were painted, the high-water marks are of
+    /// these only
+    pub painted: usize,
+    pub max_high_water_mark: usize,
+    pub mean_high_water_mark: usize,
+    pub max_resident_bytes: usize,
+    pub mean_resident_bytes: usize,
+}
+
+#[derive(Debug, Default)]
+struct UsageCounters {
+    stacks: AtomicUsize,
+    painted: AtomicUsize,
+    total_high_water_mark: AtomicUsize,
+    max_high_water_mark: AtomicUsize,
+    total_resident_bytes: AtomicUsize,
+    max_resident_bytes: AtomicUsize,
+}
+
+impl UsageCounters {
+    fn record(&self, stack: &Stack) {
+        self.stacks.fetch_add(1, Ordering::Relaxed);
+        if let Some(hwm) = stack.high_water_mark() {
+            self.painted.fetch_add(1, Ordering::Relaxed);
+            self.total_high_water_mark.fetch_add(hwm, Ordering::Relaxed);
+            self.max_high_water_mark.fetch_max(hwm, Ordering::Relaxed);
+        }
+        if let Ok(resident) = stack.resident_bytes() {
+            self.total_resident_bytes.fetch_add(resident, Ordering::Relaxed);
+            self.max_resident_bytes.fetch_max(resident, Ordering::Relaxed);
         }
     }
 
-    pub fn give_stack(&mut self, stack: Stack) {
-        if self.stacks.len() <= max_cached_stacks() {
-            self.stacks.push(stack)
+    fn get(&self) -> StackUsage {
+        let stacks = self.stacks.load(Ordering::Relaxed);
+        let painted = self.painted.load(Ordering::Relaxed);
+        StackUsage {
+            stacks,
+            painted,
+            max_high_water_mark: self.max_high_water_mark.load(Ordering::Relaxed),
+            mean_high_water_mark: self.total_high_water_mark.load(Ordering::Relaxed) / painted.max(1),
+            max_resident_bytes: self.max_resident_bytes.load(Ordering::Relaxed),
+            mean_resident_bytes: self.total_resident_bytes.load(Ordering::Relaxed) / stacks.max(1),
         }
     }
 }
 
-fn max_cached_stacks() -> usize {
-    static mut AMT: atomic::AtomicUsize = atomic::ATOMIC_USIZE_INIT;
-    match unsafe { AMT.load(atomic::Ordering::SeqCst) } {
-        0 => {}
-        n => return n - 1,
+// Get a stack the pool hasn't handed out before ready to be measured
+fn prepare(config: &StackPoolConfig, mut stack: Stack) -> Stack {
+    if config.measure {
+        stack.paint();
+    }
+    stack
+}
+
+// Measure a stack given back to a pool and get it ready for the next context
+fn recycle(config: &StackPoolConfig, usage: &UsageCounters, stack: &mut Stack) {
+    if config.measure {
+        usage.record(stack);
+    }
+
+    let decommit = match config.decommit {
+        DecommitPolicy::Never => false,
+        DecommitPolicy::Always => true,
+        DecommitPolicy::AtLeast(bytes) => stack.mapped_len() >= bytes,
+    };
+    // The stack is still fine to use if that fails. A painted stack doesn't
+    // need painting again, the pages released count as unused.
+    if decommit {
+        let _ = stack.decommit();
+    } else if config.measure {
+        stack.paint();
+    }
+}
+
+// The bucket of a stack of `size` bytes, the power of two it rounds down to
+fn size_class(size: usize) -> usize {
+    (usize::BITS - 1).saturating_sub(size.leading_zeros()) as usize
+}
+
+impl StackAllocator for StackPool {
+    type Stack = Stack;
+
+    fn allocate(&mut self, size: usize) -> Result<Stack, StackError> {
+        self.try_take_stack(size)
+    }
+
+    fn deallocate(&mut self, stack: Stack) {
+        self.give_stack(stack)
     }
-    let amt = env::var("RUST_MAX_CACHED_STACKS").ok().and_then(|s| s.parse().ok());
-    // This default corresponds to 20M of cache per scheduler (at the
-    // default size).
-    let amt = amt.unwrap_or(10);
-    // 0 is our sentinel value, so ensure that we'll never see 0 after
-    // initialization has run
-    unsafe { AMT.store(amt + 1, atomic::Ordering::SeqCst); }
-    return amt;
 }
 
 #[cfg(unix)]
@@ -182,9 +1661,35 @@ fn page_size() -> usize {
     }
 }
 
+// The default huge page size, as the kernel reports it
+#[cfg(target_os = "linux")]
+fn huge_page_size() -> usize {
+    use std::fs::File;
+    use std::io::Read;
+
+    let mut meminfo = String::new();
+    let _ = File::open("/proc/meminfo").and_then(|mut f| f.read_to_string(&mut meminfo));
+    meminfo.lines()
+        .find(|l| l.starts_with("Hugepagesize:"))
+        .and_then(|l| l.split_whitespace().nth(1))
+        .and_then(|kb| kb.parse::<usize>().ok())
+        .map(|kb| kb * 1024)
+        .unwrap_or(2 * 1024 * 1024)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn huge_page_size() -> usize {
+    page_size()
+}
+
 #[cfg(test)]
 mod tests {
-    use super::StackPool;
+    use super::{page_size, Stack, StackError, StackPool, StackPoolConfig};
+    use super::{HeapStackAllocator, StackAllocator, StackSource};
+    use super::{HugePages, StackBuilder, StackSlab};
+    use super::{DecommitPolicy, SharedStackPool, StackPoolStats, StackUsage};
+
+    use std::thread;
 
     #[test]
     fn stack_pool_caches() {
@@ -208,4 +1713,380 @@ mod tests {
         let s = p.take_stack(10);
         assert_eq!(s.min_size, 10);
     }
+
+    #[test]
+    fn try_new_rejects_invalid_sizes() {
+        match Stack::try_new(0) {
+            Err(StackError::InvalidSize(0)) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+        match Stack::try_new(page_size() - 1) {
+            Err(StackError::InvalidSize(..)) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+
+    #[test]
+    fn try_new_reports_mmap_failure() {
+        match Stack::try_new(usize::MAX / 2) {
+            Err(StackError::Mmap(..)) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+
+    #[test]
+    fn try_take_stack() {
+        let mut p = StackPool::new();
+        let s = p.try_take_stack(2 * page_size()).unwrap();
+        assert_eq!(s.min_size, 2 * page_size());
+        p.give_stack(s);
+        let s = p.try_take_stack(page_size()).unwrap();
+        assert_eq!(s.min_size, 2 * page_size());
+        assert!(p.try_take_stack(0).is_err());
+    }
+
+    #[test]
+    fn stack_pool_allocator() {
+        fn round_trip<A: StackAllocator>(a: &mut A, size: usize) -> usize {
+            let s = a.allocate(size).unwrap();
+            let len = s.end() as usize - s.start() as usize;
+            a.deallocate(s);
+            len
+        }
+
+        let mut p = StackPool::new();
+        assert_eq!(round_trip(&mut p, 4 * page_size()), 4 * page_size());
+        assert_eq!(p.len(), 1);
+        assert_eq!(round_trip(&mut HeapStackAllocator, 1024), 1024);
+        assert!(HeapStackAllocator.allocate(0).is_err());
+    }
+
+    #[test]
+    fn builder_guard_pages() {
+        let page = page_size();
+        let s = StackBuilder::new(8 * page).guard_pages(2).high_guard(true).build().unwrap();
+        assert_eq!(s.guard() as usize - s.start() as usize, 2 * page);
+        assert_eq!(s.end() as usize - s.start() as usize, 7 * page);
+
+        let s = StackBuilder::new(2 * page).guard_pages(0).build().unwrap();
+        assert_eq!(s.guard(), s.start());
+        assert_eq!(s.end() as usize - s.start() as usize, 2 * page);
+
+        // Rounded up to whole pages
+        let s = StackBuilder::new(page + 1).build().unwrap();
+        assert_eq!(s.end() as usize - s.guard() as usize, page);
+
+        match StackBuilder::new(3 * page).guard_pages(2).high_guard(true).build() {
+            Err(StackError::InvalidSize(..)) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+
+    #[test]
+    fn builder_options() {
+        let s = StackBuilder::new(16 * page_size())
+            .noreserve(true)
+            .populate(true)
+            .build()
+            .unwrap();
+        unsafe { *(s.end() as *mut usize).offset(-1) = 1 };
+
+        if cfg!(target_os = "linux") {
+            StackBuilder::new(16 * page_size())
+                .huge_pages(HugePages::Transparent)
+                .build()
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn slab_stacks() {
+        let page = page_size();
+        let slab = StackSlab::new(4 * page, 3).unwrap();
+        assert_eq!(slab.capacity(), 3);
+
+        let a = slab.take_stack().unwrap();
+        let b = slab.take_stack().unwrap();
+        let c = slab.take_stack().unwrap();
+        assert!(slab.take_stack().is_none());
+        assert_eq!(b.start() as usize - a.start() as usize, 4 * page);
+        assert_eq!(c.guard() as usize - c.start() as usize, page);
+        assert_eq!(c.end() as usize - c.start() as usize, 4 * page);
+        unsafe { *(a.end() as *mut usize).offset(-1) = 1 };
+
+        drop(b);
+        assert_eq!(slab.available(), 1);
+        let b = slab.take_stack().unwrap();
+        assert_eq!(b.start() as usize - a.start() as usize, 4 * page);
+
+        // The mapping outlives the slab as long as any of its stacks
+        drop(slab);
+        unsafe { *(c.end() as *mut usize).offset(-1) = 1 };
+
+        assert!(StackSlab::new(page, 10).is_err());
+        assert!(StackSlab::new(4 * page, 0).is_err());
+    }
+
+    #[test]
+    fn stack_pool_draws_from_slabs() {
+        let page = page_size();
+        let slab = StackSlab::new(4 * page, 1).unwrap();
+        let mut p = StackPool::new();
+        p.add_slab(slab.clone());
+
+        // Too large for the slab
+        let s = p.take_stack(8 * page);
+        assert_eq!(slab.available(), 1);
+        drop(s);
+
+        let s = p.take_stack(2 * page);
+        assert_eq!(s.min_size, 4 * page);
+        assert_eq!(slab.available(), 0);
+        p.give_stack(s);
+        let s = p.take_stack(2 * page);
+        assert_eq!(slab.available(), 0);
+        drop(s);
+        assert_eq!(slab.available(), 1);
+    }
+
+    #[test]
+    fn stack_pool_size_classes() {
+        let page = page_size();
+        let mut p = StackPool::new();
+        let small = p.take_stack(5 * page);
+        let large = p.take_stack(12 * page);
+        p.give_stack(large);
+        p.give_stack(small);
+
+        // Too small for 6 pages, it has to be the one of the next class
+        let s = p.take_stack(6 * page);
+        assert_eq!(s.min_size, 12 * page);
+        let s = p.take_stack(3 * page);
+        assert_eq!(s.min_size, 5 * page);
+        assert!(p.is_empty());
+        assert_eq!(p.cached_bytes(), 0);
+    }
+
+    #[test]
+    fn stack_pool_searches_size_class() {
+        let page = page_size();
+        let mut p = StackPool::new();
+        let large = p.take_stack(7 * page);
+        let small = p.take_stack(5 * page);
+        p.give_stack(large);
+        p.give_stack(small);
+
+        // Both are in the class of 6 pages, but only the older one fits
+        let s = p.take_stack(6 * page);
+        assert_eq!(s.min_size, 7 * page);
+        assert_eq!(p.len(), 1);
+        assert_eq!(p.cached_bytes(), p.take_stack(5 * page).mapped_len());
+        assert!(p.is_empty());
+    }
+
+    #[test]
+    fn stack_pool_evicts_across_sizes() {
+        let page = page_size();
+        let mut p = StackPool::new();
+        let a = p.take_stack(5 * page);
+        let b = p.take_stack(7 * page);
+        let c = p.take_stack(5 * page);
+        let (a_base, c_base) = (a.start(), c.start());
+        p.give_stack(a);
+        p.give_stack(b);
+        p.give_stack(c);
+
+        // The least recently given back one goes first, whatever its size
+        p.shrink_to(p.cached_bytes() - 1);
+        assert_eq!(p.len(), 2);
+        let s = p.take_stack(5 * page);
+        assert_eq!(s.start(), c_base);
+        assert!(s.start() != a_base);
+
+        // Its slot gets reused and the order still holds
+        p.give_stack(s);
+        p.shrink_to(p.cached_bytes() - 1);
+        assert_eq!(p.len(), 1);
+        assert_eq!(p.take_stack(5 * page).start(), c_base);
+        assert!(p.is_empty());
+    }
+
+    #[test]
+    fn stack_pool_budget() {
+        let page = page_size();
+        let mut p = StackPool::with_config(StackPoolConfig {
+            max_bytes: 10 * page,
+            max_stacks: 3,
+            ..StackPoolConfig::default()
+        });
+
+        let stacks: Vec<_> = (1..5).map(|n| p.take_stack((n + 1) * page)).collect();
+        for s in stacks {
+            p.give_stack(s);
+        }
+        // 2 + 3 + 4 pages, then the 5 page one pushes out the least recent
+        assert_eq!(p.len(), 2);
+        assert_eq!(p.cached_bytes(), 9 * page);
+        // The most recent one of the smallest class which fits
+        assert_eq!(p.take_stack(2 * page).min_size, 5 * page);
+
+        // Larger than the whole budget
+        p.give_stack(Stack::new(11 * page));
+        assert_eq!(p.len(), 1);
+
+        for _ in 0..5 {
+            p.give_stack(Stack::new(page));
+        }
+        assert_eq!(p.len(), 3);
+        assert_eq!(p.cached_bytes(), 3 * page);
+
+        p.shrink_to(page);
+        assert_eq!(p.len(), 1);
+        p.shrink_to(0);
+        assert!(p.is_empty());
+    }
+
+    #[test]
+    fn shared_stack_pool_across_threads() {
+        fn assert_sync<T: Send + Sync>(_: &T) {}
+
+        let page = page_size();
+        let pool = SharedStackPool::new();
+        assert_sync(&pool);
+
+        let stack = pool.take_stack(4 * page);
+        pool.give_stack(stack);
+        let stack = pool.take_stack(2 * page);
+        assert_eq!(stack.min_size, 4 * page);
+        assert_eq!(pool.stats(), StackPoolStats { hits: 1, misses: 1 });
+
+        // Given back on another thread, whose magazine goes to the shards
+        // once it exits
+        let other = pool.clone();
+        thread::spawn(move || other.give_stack(stack)).join().unwrap();
+        let stack = pool.take_stack(4 * page);
+        assert_eq!(stack.min_size, 4 * page);
+        assert_eq!(pool.stats(), StackPoolStats { hits: 2, misses: 1 });
+    }
+
+    #[test]
+    fn shared_stack_pool_spills_magazine() {
+        let page = page_size();
+        let pool = SharedStackPool::with_config(StackPoolConfig {
+            max_bytes: usize::MAX,
+            max_stacks: 1024,
+            ..StackPoolConfig::default()
+        });
+
+        // More than a magazine holds, half of it goes over to the shards
+        let stacks: Vec<_> = (0..9).map(|_| pool.take_stack(2 * page)).collect();
+        for stack in stacks {
+            pool.give_stack(stack);
+        }
+
+        let other = pool.clone();
+        thread::spawn(move || {
+            let stacks: Vec<_> = (0..5).map(|_| other.take_stack(2 * page)).collect();
+            drop(stacks);
+        }).join().unwrap();
+        assert_eq!(pool.stats(), StackPoolStats { hits: 4, misses: 10 });
+    }
+
+    #[test]
+    fn decommit_stacks() {
+        let page = page_size();
+        let top = |s: &Stack| unsafe { (s.end() as *mut usize).offset(-1) };
+        let bottom = |s: &Stack| s.guard() as *mut usize;
+
+        let mut s = Stack::new(4 * page);
+        unsafe {
+            *top(&s) = 1;
+            *bottom(&s) = 1;
+        }
+        s.decommit().unwrap();
+        unsafe {
+            assert_eq!(*top(&s), 0);
+            assert_eq!(*bottom(&s), 0);
+        }
+
+        let mut p = StackPool::with_config(StackPoolConfig {
+            decommit: DecommitPolicy::AtLeast(8 * page),
+            ..StackPoolConfig::default()
+        });
+        for &(size, kept) in &[(4 * page, 1), (8 * page, 0)] {
+            let s = p.take_stack(size);
+            unsafe { *top(&s) = 1 };
+            p.give_stack(s);
+            let s = p.take_stack(size);
+            assert_eq!(unsafe { *top(&s) }, kept);
+        }
+    }
+
+    #[test]
+    fn high_water_mark() {
+        let page = page_size();
+        let word = |s: &Stack, depth: usize| unsafe { (s.end() as *mut u8).sub(depth) as *mut usize };
+
+        let mut s = Stack::new(8 * page);
+        assert_eq!(s.high_water_mark(), None);
+        s.paint();
+        assert_eq!(s.high_water_mark(), Some(0));
+
+        unsafe { *word(&s, 800) = 1 };
+        assert_eq!(s.high_water_mark(), Some(800));
+        unsafe { *word(&s, 80) = 1 };
+        assert_eq!(s.high_water_mark(), Some(800));
+        s.paint();
+        assert_eq!(s.high_water_mark(), Some(0));
+
+        // Released pages are unused, touching them again uses them up whole
+        unsafe { *word(&s, 3 * page) = 1 };
+        s.decommit().unwrap();
+        assert_eq!(s.high_water_mark(), Some(0));
+        unsafe { *word(&s, 8) = 1 };
+        assert_eq!(s.high_water_mark(), Some(page));
+
+        let s = StackBuilder::new(8 * page).paint(true).build().unwrap();
+        assert_eq!(s.high_water_mark(), Some(0));
+    }
+
+    #[test]
+    fn resident_bytes() {
+        let page = page_size();
+        let mut s = Stack::new(16 * page);
+        assert_eq!(s.resident_bytes().unwrap(), 0);
+        unsafe {
+            *(s.end() as *mut usize).offset(-1) = 1;
+            *(s.guard() as *mut usize) = 1;
+        }
+        assert_eq!(s.resident_bytes().unwrap(), 2 * page);
+        s.decommit().unwrap();
+        assert_eq!(s.resident_bytes().unwrap(), 0);
+    }
+
+    #[test]
+    fn stack_pool_usage() {
+        let page = page_size();
+        let mut p = StackPool::with_config(StackPoolConfig {
+            measure: true,
+            ..StackPoolConfig::default()
+        });
+        for &depth in &[2 * page, page] {
+            let s = p.take_stack(8 * page);
+            unsafe { *((s.end() as *mut u8).sub(depth) as *mut usize) = 1 };
+            p.give_stack(s);
+        }
+
+        let usage = p.usage();
+        assert_eq!(usage.stacks, 2);
+        assert_eq!(usage.painted, 2);
+        assert_eq!(usage.max_high_water_mark, 2 * page);
+        assert_eq!(usage.mean_high_water_mark, 3 * page / 2);
+        // Painted all over
+        assert_eq!(usage.max_resident_bytes, 7 * page);
+
+        let pool = SharedStackPool::new();
+        pool.give_stack(pool.take_stack(8 * page));
+        assert_eq!(pool.usage(), StackUsage::default());
+    }
 }
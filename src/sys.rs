@@ -1,23 +1,15 @@
 
-/// This module is copied from `libstd/sys/common/stack.rs`.
-
-#[allow(dead_code)]
+// Parts of this module are copied from `libstd/sys/common/stack.rs`.
 
 pub mod stack {
-    pub const RED_ZONE: usize = 20 * 1024;
-
+    /// Records the bounds of the stack about to be switched to where the OS
+    /// expects them.
+    ///
+    /// Only Windows keeps them in the TIB, which it consults for growing and
+    /// probing the stack. The segmented stack limits the old runtime stored in
+    /// TLS elsewhere are long gone, so there is nothing to do on other targets.
     #[inline(always)]
-    pub unsafe fn record_rust_managed_stack_bounds(stack_lo: usize, stack_hi: usize) {
-        // When the old runtime had segmented stacks, it used a calculation that was
-        // "limit + RED_ZONE + FUDGE". The red zone was for things like dynamic
-        // symbol resolution, llvm function calls, etc. In theory this red zone
-        // value is 0, but it matters far less when we have gigantic stacks because
-        // we don't need to be so exact about our stack budget. The "fudge factor"
-        // was because LLVM doesn't emit a stack check for functions < 256 bytes in
-        // size. Again though, we have giant stacks, so we round all these
-        // calculations up to the nice round number of 20k.
-        record_sp_limit(stack_lo + RED_ZONE);
-
+    pub unsafe fn record_os_stack_bounds(stack_lo: usize, stack_hi: usize) {
         return target_record_stack_bounds(stack_lo, stack_hi);
 
         #[cfg(not(windows))] #[inline(always)]
@@ -25,204 +17,192 @@ pub mod stack {
 
         #[cfg(all(windows, target_arch = "x86"))] #[inline(always)]
         unsafe fn target_record_stack_bounds(stack_lo: usize, stack_hi: usize) {
+            use std::arch::asm;
             // stack range is at TIB: %fs:0x04 (top) and %fs:0x08 (bottom)
-            asm!("mov $0, %fs:0x04" :: "r"(stack_hi) :: "volatile");
-            asm!("mov $0, %fs:0x08" :: "r"(stack_lo) :: "volatile");
+            asm!("mov {0}, %fs:0x04", in(reg) stack_hi, options(att_syntax, nostack, preserves_flags));
+            asm!("mov {0}, %fs:0x08", in(reg) stack_lo, options(att_syntax, nostack, preserves_flags));
         }
         #[cfg(all(windows, target_arch = "x86_64"))] #[inline(always)]
         unsafe fn target_record_stack_bounds(stack_lo: usize, stack_hi: usize) {
+            use std::arch::asm;
             // stack range is at TIB: %gs:0x08 (top) and %gs:0x10 (bottom)
-            asm!("mov $0, %gs:0x08" :: "r"(stack_hi) :: "volatile");
-            asm!("mov $0, %gs:0x10" :: "r"(stack_lo) :: "volatile");
+            asm!("mov {0}, %gs:0x08", in(reg) stack_hi, options(att_syntax, nostack, preserves_flags));
+            asm!("mov {0}, %gs:0x10", in(reg) stack_lo, options(att_syntax, nostack, preserves_flags));
         }
     }
+}
 
-    /// Records the current limit of the stack as specified by `end`.
-    ///
-    /// This is stored in an OS-dependent location, likely inside of the thread
-    /// local storage. The location that the limit is stored is a pre-ordained
-    /// location because it's where LLVM has emitted code to check.
-    ///
-    /// Note that this cannot be called under normal circumstances. This function is
-    /// changing the stack limit, so upon returning any further function calls will
-    /// possibly be triggering the morestack logic if you're not careful.
-    ///
-    /// Also note that this and all of the inside functions are all flagged as
-    /// "inline(always)" because they're messing around with the stack limits.  This
-    /// would be unfortunate for the functions themselves to trigger a morestack
-    /// invocation (if they were an actual function call).
-    #[inline(always)]
-    pub unsafe fn record_sp_limit(limit: usize) {
-        return target_record_sp_limit(limit);
-
-        // x86-64
-        #[cfg(all(target_arch = "x86_64",
-                  any(target_os = "macos", target_os = "ios")))]
-        #[inline(always)]
-        unsafe fn target_record_sp_limit(limit: usize) {
-            asm!("movq $$0x60+90*8, %rsi
-                  movq $0, %gs:(%rsi)" :: "r"(limit) : "rsi" : "volatile")
-        }
-        #[cfg(all(target_arch = "x86_64", target_os = "linux"))] #[inline(always)]
-        unsafe fn target_record_sp_limit(limit: usize) {
-            asm!("movq $0, %fs:112" :: "r"(limit) :: "volatile")
-        }
-        #[cfg(all(target_arch = "x86_64", target_os = "windows"))] #[inline(always)]
-        unsafe fn target_record_sp_limit(_: usize) {
-        }
-        #[cfg(all(target_arch = "x86_64", target_os = "freebsd"))] #[inline(always)]
-        unsafe fn target_record_sp_limit(limit: usize) {
-            asm!("movq $0, %fs:24" :: "r"(limit) :: "volatile")
-        }
-        #[cfg(all(target_arch = "x86_64", target_os = "dragonfly"))]
-        #[inline(always)]
-        unsafe fn target_record_sp_limit(limit: usize) {
-            asm!("movq $0, %fs:32" :: "r"(limit) :: "volatile")
-        }
+// The bits of <signal.h> the overflow handler needs, which the libc crate
+// doesn't have
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+#[allow(non_camel_case_types)]
+pub mod signal {
+    use libc;
+
+    pub use libc::SIGSEGV;
+    #[cfg(all(target_os = "linux", not(any(target_arch = "mips", target_arch = "mips64"))))]
+    pub const SIGBUS: libc::c_int = 7;
+    #[cfg(any(target_os = "macos", target_arch = "mips", target_arch = "mips64"))]
+    pub const SIGBUS: libc::c_int = 10;
+
+    pub const SIG_DFL: usize = 0;
+    pub const SIG_IGN: usize = 1;
+
+    #[cfg(all(target_os = "linux", not(any(target_arch = "mips", target_arch = "mips64"))))]
+    pub const SA_SIGINFO: libc::c_int = 4;
+    #[cfg(all(target_os = "linux", any(target_arch = "mips", target_arch = "mips64")))]
+    pub const SA_SIGINFO: libc::c_int = 8;
+    #[cfg(target_os = "linux")]
+    pub const SA_ONSTACK: libc::c_int = 0x0800_0000;
+    #[cfg(target_os = "macos")]
+    pub const SA_SIGINFO: libc::c_int = 0x40;
+    #[cfg(target_os = "macos")]
+    pub const SA_ONSTACK: libc::c_int = 1;
+
+    #[cfg(target_os = "linux")]
+    pub const SS_DISABLE: libc::c_int = 2;
+    #[cfg(target_os = "macos")]
+    pub const SS_DISABLE: libc::c_int = 4;
+
+    #[cfg(target_os = "linux")]
+    pub type sigset_t = [libc::c_ulong; 128 / ::std::mem::size_of::<libc::c_ulong>()];
+    #[cfg(target_os = "macos")]
+    pub type sigset_t = u32;
+
+    #[cfg(all(target_os = "linux", not(any(target_arch = "mips", target_arch = "mips64"))))]
+    #[repr(C)]
+    pub struct sigaction {
+        pub sa_sigaction: usize,
+        pub sa_mask: sigset_t,
+        pub sa_flags: libc::c_int,
+        pub sa_restorer: usize,
+    }
 
-        // x86
-        #[cfg(all(target_arch = "x86",
-                  any(target_os = "macos", target_os = "ios")))]
-        #[inline(always)]
-        unsafe fn target_record_sp_limit(limit: usize) {
-            asm!("movl $$0x48+90*4, %eax
-                  movl $0, %gs:(%eax)" :: "r"(limit) : "eax" : "volatile")
-        }
-        #[cfg(all(target_arch = "x86",
-                  any(target_os = "linux", target_os = "freebsd")))]
-        #[inline(always)]
-        unsafe fn target_record_sp_limit(limit: usize) {
-            asm!("movl $0, %gs:48" :: "r"(limit) :: "volatile")
-        }
-        #[cfg(all(target_arch = "x86", target_os = "windows"))] #[inline(always)]
-        unsafe fn target_record_sp_limit(_: usize) {
-        }
+    #[cfg(all(target_os = "linux", any(target_arch = "mips", target_arch = "mips64")))]
+    #[repr(C)]
+    pub struct sigaction {
+        pub sa_flags: libc::c_int,
+        pub sa_sigaction: usize,
+        pub sa_mask: sigset_t,
+        pub sa_restorer: usize,
+    }
 
-        // mips, arm - Some brave soul can port these to inline asm, but it's over
-        //             my head personally
-        #[cfg(any(target_arch = "mips",
-                  target_arch = "mipsel",
-                  all(target_arch = "arm", not(target_os = "ios"))))]
-        #[inline(always)]
-        unsafe fn target_record_sp_limit(limit: usize) {
-            use libc::c_void;
-            return record_sp_limit(limit as *const c_void);
-            extern {
-                fn record_sp_limit(limit: *const c_void);
-            }
-        }
+    #[cfg(target_os = "macos")]
+    #[repr(C)]
+    pub struct sigaction {
+        pub sa_sigaction: usize,
+        pub sa_mask: sigset_t,
+        pub sa_flags: libc::c_int,
+    }
 
-        // aarch64 - FIXME(AARCH64): missing...
-        // powerpc - FIXME(POWERPC): missing...
-        // arm-ios - iOS segmented stack is disabled for now, see related notes
-        // openbsd - segmented stack is disabled
-        #[cfg(any(target_arch = "aarch64",
-                  target_arch = "powerpc",
-                  all(target_arch = "arm", target_os = "ios"),
-                  target_os = "bitrig",
-                  target_os = "openbsd"))]
-        unsafe fn target_record_sp_limit(_: usize) {
-        }
+    // Only the fields up to the faulting address
+    #[cfg(target_os = "linux")]
+    #[repr(C)]
+    pub struct siginfo_t {
+        pub si_signo: libc::c_int,
+        _si_errno_or_code: [libc::c_int; 2],
+        pub si_addr: *mut libc::c_void,
     }
 
-    /// The counterpart of the function above, this function will fetch the current
-    /// stack limit stored in TLS.
-    ///
-    /// Note that all of these functions are meant to be exact counterparts of their
-    /// brethren above, except that the operands are reversed.
-    ///
-    /// As with the setter, this function does not have a __morestack header and can
-    /// therefore be called in a "we're out of stack" situation.
-    #[inline(always)]
-    pub unsafe fn get_sp_limit() -> usize {
-        return target_get_sp_limit();
-
-        // x86-64
-        #[cfg(all(target_arch = "x86_64",
-                  any(target_os = "macos", target_os = "ios")))]
-        #[inline(always)]
-        unsafe fn target_get_sp_limit() -> usize {
-            let limit;
-            asm!("movq $$0x60+90*8, %rsi
-                  movq %gs:(%rsi), $0" : "=r"(limit) :: "rsi" : "volatile");
-            return limit;
-        }
-        #[cfg(all(target_arch = "x86_64", target_os = "linux"))] #[inline(always)]
-        unsafe fn target_get_sp_limit() -> usize {
-            let limit;
-            asm!("movq %fs:112, $0" : "=r"(limit) ::: "volatile");
-            return limit;
-        }
-        #[cfg(all(target_arch = "x86_64", target_os = "windows"))] #[inline(always)]
-        unsafe fn target_get_sp_limit() -> usize {
-            return 1024;
-        }
-        #[cfg(all(target_arch = "x86_64", target_os = "freebsd"))] #[inline(always)]
-        unsafe fn target_get_sp_limit() -> usize {
-            let limit;
-            asm!("movq %fs:24, $0" : "=r"(limit) ::: "volatile");
-            return limit;
-        }
-        #[cfg(all(target_arch = "x86_64", target_os = "dragonfly"))]
-        #[inline(always)]
-        unsafe fn target_get_sp_limit() -> usize {
-            let limit;
-            asm!("movq %fs:32, $0" : "=r"(limit) ::: "volatile");
-            return limit;
-        }
+    #[cfg(target_os = "macos")]
+    #[repr(C)]
+    pub struct siginfo_t {
+        pub si_signo: libc::c_int,
+        _si_errno_to_status: [libc::c_int; 5],
+        pub si_addr: *mut libc::c_void,
+    }
 
-        // x86
-        #[cfg(all(target_arch = "x86",
-                  any(target_os = "macos", target_os = "ios")))]
-        #[inline(always)]
-        unsafe fn target_get_sp_limit() -> usize {
-            let limit;
-            asm!("movl $$0x48+90*4, %eax
-                  movl %gs:(%eax), $0" : "=r"(limit) :: "eax" : "volatile");
-            return limit;
-        }
-        #[cfg(all(target_arch = "x86",
-                  any(target_os = "linux", target_os = "freebsd")))]
-        #[inline(always)]
-        unsafe fn target_get_sp_limit() -> usize {
-            let limit;
-            asm!("movl %gs:48, $0" : "=r"(limit) ::: "volatile");
-            return limit;
+    #[cfg(all(target_os = "linux", not(any(target_arch = "mips", target_arch = "mips64"))))]
+    #[repr(C)]
+    pub struct stack_t {
+        pub ss_sp: *mut libc::c_void,
+        pub ss_flags: libc::c_int,
+        pub ss_size: libc::size_t,
+    }
+
+    #[cfg(any(target_os = "macos", target_arch = "mips", target_arch = "mips64"))]
+    #[repr(C)]
+    pub struct stack_t {
+        pub ss_sp: *mut libc::c_void,
+        pub ss_size: libc::size_t,
+        pub ss_flags: libc::c_int,
+    }
+
+    extern "C" {
+        pub fn sigaction(signum: libc::c_int, act: *const sigaction, oldact: *mut sigaction) -> libc::c_int;
+        pub fn sigaltstack(ss: *const stack_t, old_ss: *mut stack_t) -> libc::c_int;
+    }
+}
+
+pub mod thread {
+    /// The lower and upper bound of the stack of the calling thread, as the
+    /// OS keeps them, or `None` if they can't be found out.
+    pub fn stack_bounds() -> Option<(usize, usize)> {
+        return unsafe { target_stack_bounds() };
+
+        #[cfg(target_os = "linux")]
+        unsafe fn target_stack_bounds() -> Option<(usize, usize)> {
+            use libc;
+            use std::mem;
+            use std::ptr;
+
+            // Larger than a pthread_attr_t on any target
+            #[allow(non_camel_case_types)]
+            #[repr(C)]
+            struct pthread_attr_t([u64; 16]);
+
+            extern "C" {
+                fn pthread_self() -> libc::pthread_t;
+                fn pthread_getattr_np(thread: libc::pthread_t, attr: *mut pthread_attr_t) -> libc::c_int;
+                fn pthread_attr_getstack(attr: *const pthread_attr_t, stackaddr: *mut *mut libc::c_void,
+                                         stacksize: *mut usize) -> libc::c_int;
+                fn pthread_attr_destroy(attr: *mut pthread_attr_t) -> libc::c_int;
+            }
+
+            let mut attr: pthread_attr_t = mem::zeroed();
+            if pthread_getattr_np(pthread_self(), &mut attr) != 0 {
+                return None;
+            }
+            let mut addr = ptr::null_mut();
+            let mut size = 0;
+            let ret = pthread_attr_getstack(&attr, &mut addr, &mut size);
+            pthread_attr_destroy(&mut attr);
+            if ret != 0 {
+                return None;
+            }
+            Some((addr as usize, addr as usize + size))
         }
-        #[cfg(all(target_arch = "x86", target_os = "windows"))] #[inline(always)]
-        unsafe fn target_get_sp_limit() -> usize {
-            return 1024;
+
+        #[cfg(target_os = "macos")]
+        unsafe fn target_stack_bounds() -> Option<(usize, usize)> {
+            use libc;
+
+            extern "C" {
+                fn pthread_self() -> libc::pthread_t;
+                fn pthread_get_stackaddr_np(thread: libc::pthread_t) -> *mut libc::c_void;
+                fn pthread_get_stacksize_np(thread: libc::pthread_t) -> usize;
+            }
+
+            // The address is the high end of the stack
+            let thread = pthread_self();
+            let hi = pthread_get_stackaddr_np(thread) as usize;
+            Some((hi - pthread_get_stacksize_np(thread), hi))
         }
 
-        // mips, arm - Some brave soul can port these to inline asm, but it's over
-        //             my head personally
-        #[cfg(any(target_arch = "mips",
-                  target_arch = "mipsel",
-                  all(target_arch = "arm", not(target_os = "ios"))))]
-        #[inline(always)]
-        unsafe fn target_get_sp_limit() -> usize {
-            use libc::c_void;
-            return get_sp_limit() as usize;
-            extern {
-                fn get_sp_limit() -> *const c_void;
+        #[cfg(windows)]
+        unsafe fn target_stack_bounds() -> Option<(usize, usize)> {
+            extern "system" {
+                fn GetCurrentThreadStackLimits(low: *mut usize, high: *mut usize);
             }
+
+            let (mut lo, mut hi) = (0, 0);
+            GetCurrentThreadStackLimits(&mut lo, &mut hi);
+            Some((lo, hi))
         }
 
-        // aarch64 - FIXME(AARCH64): missing...
-        // powerpc - FIXME(POWERPC): missing...
-        // arm-ios - iOS doesn't support segmented stacks yet.
-        // openbsd - OpenBSD doesn't support segmented stacks.
-        //
-        // This function might be called by runtime though
-        // so it is unsafe to unreachable, let's return a fixed constant.
-        #[cfg(any(target_arch = "aarch64",
-                  target_arch = "powerpc",
-                  all(target_arch = "arm", target_os = "ios"),
-                  target_os = "bitrig",
-                  target_os = "openbsd"))]
-        #[inline(always)]
-        unsafe fn target_get_sp_limit() -> usize {
-            1024
+        #[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+        unsafe fn target_stack_bounds() -> Option<(usize, usize)> {
+            None
         }
     }
 }